@@ -1,20 +1,89 @@
+use std::collections::HashMap;
+
 use anyhow::{Result, anyhow};
+use thiserror::Error;
+
+/// Classic DNS over UDP limit (RFC 1035 section 4.2.1)
+pub const UDP_MAX_SIZE: usize = 512;
+
+/// Largest message that fits behind a two-byte TCP length prefix
+pub const TCP_MAX_SIZE: usize = 65535;
+
+/// Returned when a write would grow the message past the buffer's maximum
+/// size, so callers can tell running out of room apart from bad data.
+#[derive(Debug, Error)]
+#[error("Message exceeds {0} bytes")]
+pub struct BufferFull(pub usize);
 
 pub struct BytePacketBuffer {
-    pub buf: [u8; 512],
+    pub buf: Vec<u8>,
     pub pos: usize,
+    max_size: usize,
+    compress: bool,
+    names: HashMap<String, usize>,
 }
 
 impl BytePacketBuffer {
     /// This gives us a fresh buffer for holding the packet contents, and a
     /// field for keeping track of where we are.
     pub fn new() -> BytePacketBuffer {
+        BytePacketBuffer::with_max_size(UDP_MAX_SIZE)
+    }
+
+    /// A fresh buffer that grows on write up to `max_size` bytes, e.g. the
+    /// EDNS payload size a client advertised or `TCP_MAX_SIZE`.
+    pub fn with_max_size(max_size: usize) -> BytePacketBuffer {
         BytePacketBuffer {
-            buf: [0; 512],
+            buf: Vec::with_capacity(max_size.min(UDP_MAX_SIZE)),
             pos: 0,
+            max_size: max_size.min(TCP_MAX_SIZE),
+            compress: true,
+            names: HashMap::new(),
         }
     }
 
+    /// Wrap a received message for reading
+    pub fn from_bytes(data: &[u8]) -> BytePacketBuffer {
+        BytePacketBuffer {
+            buf: data.to_vec(),
+            pos: 0,
+            max_size: TCP_MAX_SIZE,
+            compress: true,
+            names: HashMap::new(),
+        }
+    }
+
+    /// Turn name compression on or off for subsequent writes. Canonical wire
+    /// form (RFC 4034 section 6.2), as used for DNSSEC, requires it off.
+    #[allow(dead_code)]
+    pub fn set_compression(&mut self, enabled: bool) {
+        self.compress = enabled;
+    }
+
+    /// Hold back `len` bytes at the end of the buffer for something that has
+    /// to be written last whatever else gets dropped
+    pub fn reserve(&mut self, len: usize) {
+        self.max_size = self.max_size.saturating_sub(len);
+    }
+
+    /// Give back space held by `reserve`
+    pub fn release(&mut self, len: usize) {
+        self.max_size = (self.max_size + len).min(TCP_MAX_SIZE);
+    }
+
+    /// The bytes written so far
+    pub fn as_slice(&self) -> &[u8] {
+        &self.buf[..self.pos]
+    }
+
+    /// Roll back everything written from `pos` onwards, including any
+    /// compression targets that pointed into the discarded bytes
+    pub fn truncate(&mut self, pos: usize) {
+        self.buf.truncate(pos);
+        self.pos = pos;
+        self.names.retain(|_, offset| *offset < pos);
+    }
+
     /// Current position within buffer
     pub fn pos(&self) -> usize {
         self.pos
@@ -27,14 +96,14 @@ impl BytePacketBuffer {
     }
 
     /// Change the buffer position
-    fn seek(&mut self, pos: usize) -> Result<()> {
+    pub fn seek(&mut self, pos: usize) -> Result<()> {
         self.pos = pos;
         Ok(())
     }
 
     /// Read a single byte and move the position one step forward
     fn read(&mut self) -> Result<u8> {
-        if self.pos >= 512 {
+        if self.pos >= self.buf.len() {
             return Err(anyhow!("End of buffer"));
         }
         let res = self.buf[self.pos];
@@ -44,7 +113,7 @@ impl BytePacketBuffer {
 
     /// Get a single byte, without changing the buffer position
     fn get(&mut self, pos: usize) -> Result<u8> {
-        if pos >= 512 {
+        if pos >= self.buf.len() {
             return Err(anyhow!("End of buffer"));
         }
         Ok(self.buf[pos])
@@ -52,12 +121,17 @@ impl BytePacketBuffer {
 
     /// Get a range of bytes
     fn get_range(&mut self, start: usize, len: usize) -> Result<&[u8]> {
-        if start + len >= 512 {
+        if start + len > self.buf.len() {
             return Err(anyhow!("End of buffer"));
         }
         Ok(&self.buf[start..start + len])
     }
 
+    /// Read a single byte, stepping one step forward
+    pub fn read_u8(&mut self) -> Result<u8> {
+        self.read()
+    }
+
     /// Read two bytes, stepping two steps forward
     pub fn read_u16(&mut self) -> Result<u16> {
         //println!("Buffer at position {}: {:02X?}", self.pos, &self.buf[self.pos..self.pos + 2]);
@@ -76,6 +150,13 @@ impl BytePacketBuffer {
         Ok(res)
     }
 
+    /// Read a run of raw bytes, stepping past them
+    pub fn read_bytes(&mut self, len: usize) -> Result<Vec<u8>> {
+        let bytes = self.get_range(self.pos, len)?.to_vec();
+        self.step(len)?;
+        Ok(bytes)
+    }
+
     /// Read a qname
     pub fn read_qname(&mut self, outstr: &mut String) -> Result<()> {
         let mut pos = self.pos();
@@ -129,10 +210,15 @@ impl BytePacketBuffer {
     }
 
     pub fn write(&mut self, val: u8) -> Result<()> {
-        if self.pos >= 512 {
-            return Err(anyhow!("End of buffer"));
+        if self.pos >= self.max_size {
+            return Err(BufferFull(self.max_size).into());
+        }
+        if self.pos < self.buf.len() {
+            self.buf[self.pos] = val;
+        } else {
+            self.buf.resize(self.pos, 0);
+            self.buf.push(val);
         }
-        self.buf[self.pos] = val;
         self.pos += 1;
         Ok(())
     }
@@ -154,22 +240,70 @@ impl BytePacketBuffer {
         self.write(((val >> 24) & 0xFF) as u8)?;
         self.write(((val >> 16) & 0xFF) as u8)?;
         self.write(((val >> 8) & 0xFF) as u8)?;
-        self.write(((val >> 0) & 0xFF) as u8)?;
+        self.write((val & 0xFF) as u8)?;
+
+        Ok(())
+    }
+
+    pub fn write_bytes(&mut self, bytes: &[u8]) -> Result<()> {
+        for b in bytes {
+            self.write(*b)?;
+        }
 
         Ok(())
     }
 
+    /// Overwrite a single byte at an earlier position
+    fn set(&mut self, pos: usize, val: u8) -> Result<()> {
+        if pos >= self.buf.len() {
+            return Err(anyhow!("End of buffer"));
+        }
+        self.buf[pos] = val;
+        Ok(())
+    }
+
+    /// Overwrite two bytes at an earlier position, used to backfill lengths
+    pub fn set_u16(&mut self, pos: usize, val: u16) -> Result<()> {
+        self.set(pos, (val >> 8) as u8)?;
+        self.set(pos + 1, (val & 0xFF) as u8)?;
+
+        Ok(())
+    }
+
+    /// Write a qname, replacing any suffix that has already been written to
+    /// this buffer with a pointer to it (RFC 1035 section 4.1.4)
     pub fn write_qname(&mut self, qname: &str) -> Result<()> {
-        for label in qname.split('.') {
+        self.write_labels(qname, self.compress)
+    }
+
+    /// Write a qname in full, for RDATA that must not be compressed (SRV)
+    pub fn write_qname_uncompressed(&mut self, qname: &str) -> Result<()> {
+        self.write_labels(qname, false)
+    }
+
+    fn write_labels(&mut self, qname: &str, compress: bool) -> Result<()> {
+        let labels: Vec<&str> = qname.split('.').filter(|l| !l.is_empty()).collect();
+
+        for (i, label) in labels.iter().enumerate() {
             let len = label.len();
             if len > 0x3f {
                 return Err(anyhow!("Single label exceeds 63 characters of length"));
             }
 
-            self.write_u8(len as u8)?;
-            for b in label.as_bytes() {
-                self.write_u8(*b)?;
+            if compress {
+                let suffix = labels[i..].join(".").to_lowercase();
+                if let Some(&offset) = self.names.get(&suffix) {
+                    return self.write_u16(0xC000 | offset as u16);
+                }
+
+                // Pointers only have 14 bits for the offset
+                if self.pos <= 0x3FFF {
+                    self.names.insert(suffix, self.pos);
+                }
             }
+
+            self.write_u8(len as u8)?;
+            self.write_bytes(label.as_bytes())?;
         }
 
         self.write_u8(0)?;
@@ -178,3 +312,95 @@ impl BytePacketBuffer {
     }
 
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        packet::DnsPacket,
+        query::{DnsQuestion, QueryClass, QueryType},
+        record::DnsRecord,
+        testing::{a, ns},
+    };
+
+    /// `name` as uncompressed labels
+    fn labels(name: &str) -> Vec<u8> {
+        let mut out = BytePacketBuffer::with_max_size(UDP_MAX_SIZE);
+        out.write_qname_uncompressed(name).unwrap();
+        out.as_slice().to_vec()
+    }
+
+    fn packet() -> DnsPacket {
+        let mut packet = DnsPacket::new();
+        packet.questions.push(DnsQuestion::new("www.example.com".to_string(), QueryType::A));
+        packet.answers.push(DnsRecord::CNAME {
+            domain: "www.example.com".to_string(),
+            class: QueryClass::IN,
+            host: "mail.EXAMPLE.com".to_string(),
+            ttl: 300,
+        });
+        packet.answers.push(a("mail.example.com", [192, 0, 2, 1], 300));
+        packet.authorities.push(ns("example.com", "ns1.example.com"));
+        packet.resources.push(a("ns1.example.com", [192, 0, 2, 53], 300));
+        packet
+    }
+
+    #[test]
+    fn repeated_names_become_pointers_and_read_back() {
+        let mut buffer = BytePacketBuffer::with_max_size(UDP_MAX_SIZE);
+        packet().write(&mut buffer).unwrap();
+        let bytes = buffer.as_slice().to_vec();
+
+        // The question name starts right after the header, and the answer
+        // owner right after the question's type and class
+        let answer = 12 + labels("www.example.com").len() + 4;
+        assert_eq!(bytes[answer..answer + 2], [0xC0, 12]);
+        // The CNAME target's suffix matches example.com in the question,
+        // whatever its case
+        let rdata = answer + 2 + 10;
+        assert_eq!(bytes[rdata..rdata + 7], [4, b'm', b'a', b'i', b'l', 0xC0, 16]);
+        // And the next owner points into that RDATA
+        assert_eq!(bytes[rdata + 7..rdata + 9], [0xC0, rdata as u8]);
+
+        let mut uncompressed = BytePacketBuffer::with_max_size(UDP_MAX_SIZE);
+        uncompressed.set_compression(false);
+        packet().write(&mut uncompressed).unwrap();
+        assert!(bytes.len() < uncompressed.pos());
+
+        let parsed = DnsPacket::from_buffer(&mut BytePacketBuffer::from_bytes(&bytes)).unwrap();
+        assert_eq!(parsed.questions, packet().questions);
+        assert_eq!(parsed.answers[0].domain(), "www.example.com");
+        assert!(matches!(&parsed.answers[0], DnsRecord::CNAME { host, .. } if host == "mail.example.com"));
+        assert_eq!(parsed.answers[1..], packet().answers[1..]);
+        assert_eq!(parsed.authorities, packet().authorities);
+        assert_eq!(parsed.resources, packet().resources);
+    }
+
+    #[test]
+    fn truncate_forgets_names_past_the_cut() {
+        let mut buffer = BytePacketBuffer::with_max_size(UDP_MAX_SIZE);
+        buffer.write_qname("a.example.com").unwrap();
+        let cut = buffer.pos();
+        buffer.write_qname("b.test.org").unwrap();
+        buffer.truncate(cut);
+
+        buffer.write_qname("c.test.org").unwrap();
+        assert_eq!(buffer.as_slice()[cut..], labels("c.test.org")[..]);
+
+        // Names from before the cut are still pointed at
+        let pos = buffer.pos();
+        buffer.write_qname("example.com").unwrap();
+        assert_eq!(buffer.as_slice()[pos..], [0xC0, 2]);
+    }
+
+    #[test]
+    fn names_past_the_pointer_range_are_not_targets() {
+        let mut buffer = BytePacketBuffer::with_max_size(TCP_MAX_SIZE);
+        buffer.write_bytes(&[0; 0x4000]).unwrap();
+        buffer.write_qname("far.example.net").unwrap();
+
+        let pos = buffer.pos();
+        buffer.write_qname("far.example.net").unwrap();
+        assert_eq!(buffer.as_slice()[pos..], labels("far.example.net")[..]);
+    }
+}
@@ -0,0 +1,452 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::{
+    header::ResultCode,
+    packet::{DnsPacket, OPT_TYPE},
+    query::{DnsQuestion, QueryClass, QueryType},
+    record::DnsRecord,
+};
+
+/// Entries are dropped after a day at most, whatever TTL upstream gave
+const MAX_TTL: u32 = 86400;
+
+/// TTL given to records in a stale answer (RFC 8767 section 4)
+const STALE_TTL: u32 = 30;
+
+/// How long after a failed refresh stale answers are served without asking
+/// upstream again (the failure recheck timer, RFC 8767 section 5)
+const REFRESH_RECHECK: Duration = Duration::from_secs(30);
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+struct CacheKey {
+    name: String,
+    qtype: QueryType,
+    class: QueryClass,
+}
+
+impl CacheKey {
+    fn new(question: &DnsQuestion) -> CacheKey {
+        CacheKey {
+            name: question.name.to_lowercase(),
+            qtype: question.qtype,
+            class: question.class,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct CacheEntry {
+    rescode: ResultCode,
+    answers: Vec<DnsRecord>,
+    authorities: Vec<DnsRecord>,
+    resources: Vec<DnsRecord>,
+    inserted: Instant,
+    /// Lifetime of the entry: the smallest TTL among its records
+    ttl: u32,
+    /// Position in the LRU order, bumped on every hit
+    last_used: u64,
+    /// Fresh answers served from this entry
+    hits: u64,
+    /// Set while a prefetch or stale refresh is running, so only one runs
+    /// at a time
+    refreshing: bool,
+    /// When the last refresh failed, to hold off retrying for a while
+    refresh_failed: Option<Instant>,
+}
+
+#[derive(Debug, Default)]
+struct CacheInner {
+    entries: HashMap<CacheKey, CacheEntry>,
+    /// `last_used` -> key, oldest first, for picking eviction victims
+    lru: BTreeMap<u64, CacheKey>,
+    tick: u64,
+}
+
+/// When to refresh a popular entry before it expires
+#[derive(Clone, Copy, Debug)]
+pub struct PrefetchOptions {
+    /// Share of the original TTL, in percent, that may remain when a refresh
+    /// is started. 0 turns prefetching off.
+    pub percent: u32,
+    /// Hits an entry needs before it is worth refreshing early
+    pub min_hits: u64,
+}
+
+impl Default for PrefetchOptions {
+    fn default() -> Self {
+        PrefetchOptions {
+            percent: 10,
+            min_hits: 3,
+        }
+    }
+}
+
+/// Responses from upstream keyed on (name, qtype, class), served back with
+/// TTLs counted down by the time spent in the cache. Holds at most
+/// `max_entries`, evicting the least recently used entry to make room.
+/// Expired entries are kept for a further `stale_window` so they can be
+/// served stale when upstream is unreachable (RFC 8767).
+#[derive(Debug)]
+pub struct Cache {
+    inner: Mutex<CacheInner>,
+    max_entries: usize,
+    stale_window: Duration,
+    prefetch: PrefetchOptions,
+}
+
+impl Cache {
+    pub fn new(max_entries: usize, stale_window: Duration, prefetch: PrefetchOptions) -> Cache {
+        Cache {
+            inner: Mutex::new(CacheInner::default()),
+            max_entries,
+            stale_window,
+            prefetch,
+        }
+    }
+
+    /// A cached response to `question`, if there is one that hasn't expired
+    pub fn get(&self, question: &DnsQuestion) -> Option<DnsPacket> {
+        let key = CacheKey::new(question);
+        let mut inner = self.inner.lock().unwrap();
+
+        let entry = inner.entries.get(&key)?;
+        let elapsed = entry.inserted.elapsed();
+        if elapsed >= entry.expiry() {
+            if elapsed >= entry.expiry() + self.stale_window {
+                inner.remove(&key);
+            }
+            return None;
+        }
+
+        inner.touch(&key);
+        let entry = inner.entries.get_mut(&key)?;
+        entry.hits += 1;
+
+        let elapsed = elapsed.as_secs() as u32;
+        Some(entry.to_packet(question, |ttl| ttl.saturating_sub(elapsed)))
+    }
+
+    /// Whether the entry for `question` is popular and close enough to
+    /// expiry that it should be refreshed now. Returns true at most once per
+    /// cached response, so the caller can start a single background refresh.
+    pub fn claim_prefetch(&self, question: &DnsQuestion) -> bool {
+        if self.prefetch.percent == 0 {
+            return false;
+        }
+
+        let key = CacheKey::new(question);
+        let mut inner = self.inner.lock().unwrap();
+        let Some(entry) = inner.entries.get_mut(&key) else {
+            return false;
+        };
+
+        if !entry.may_refresh() || entry.hits < self.prefetch.min_hits {
+            return false;
+        }
+
+        let expiry = entry.expiry();
+        let remaining = expiry.saturating_sub(entry.inserted.elapsed());
+        if remaining.is_zero() || remaining * 100 > expiry * self.prefetch.percent {
+            return false;
+        }
+
+        entry.refreshing = true;
+        true
+    }
+
+    /// Whether the caller should refresh the stale entry for `question`.
+    /// Returns false while another refresh is running or shortly after one
+    /// failed, so an unreachable upstream isn't asked again by every query.
+    pub fn claim_refresh(&self, question: &DnsQuestion) -> bool {
+        let key = CacheKey::new(question);
+        let mut inner = self.inner.lock().unwrap();
+        let Some(entry) = inner.entries.get_mut(&key) else {
+            return true;
+        };
+
+        if !entry.may_refresh() {
+            return false;
+        }
+
+        entry.refreshing = true;
+        true
+    }
+
+    /// Record that a prefetch or stale refresh of `question` has finished.
+    /// A successful one has usually replaced the entry already, but not if
+    /// the new answer couldn't be cached.
+    pub fn finish_refresh(&self, question: &DnsQuestion, failed: bool) {
+        let key = CacheKey::new(question);
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(entry) = inner.entries.get_mut(&key) {
+            entry.refreshing = false;
+            if failed {
+                entry.refresh_failed = Some(Instant::now());
+            }
+        }
+    }
+
+    /// An expired response to `question` that is still inside the stale
+    /// window, with every TTL set to `STALE_TTL`
+    pub fn get_stale(&self, question: &DnsQuestion) -> Option<DnsPacket> {
+        let key = CacheKey::new(question);
+        let mut inner = self.inner.lock().unwrap();
+
+        let entry = inner.entries.get(&key)?;
+        let elapsed = entry.inserted.elapsed();
+        if elapsed < entry.expiry() || elapsed >= entry.expiry() + self.stale_window {
+            return None;
+        }
+
+        inner.touch(&key);
+        Some(inner.entries[&key].to_packet(question, |_| STALE_TTL))
+    }
+
+    /// Remember an upstream response to `question`. Positive answers live
+    /// as long as their smallest TTL; NXDOMAIN and NODATA answers are cached
+    /// per RFC 2308 using the SOA from the authority section. Anything with a
+    /// zero TTL, or a negative answer without an SOA, is not cached at all.
+    pub fn insert(&self, question: &DnsQuestion, response: &DnsPacket) {
+        if self.max_entries == 0 || response.header.truncated_message {
+            return;
+        }
+
+        // Negative answers may still carry the CNAME chain that led to the
+        // missing name or type, which must be kept (RFC 2308 section 2)
+        let negative = match response.header.rescode {
+            ResultCode::NXDOMAIN => true,
+            ResultCode::NOERROR => !response.answers.iter().any(|rec| rec.qtype() == question.qtype),
+            _ => return,
+        };
+
+        let (ttl, answers, authorities, resources) = if negative {
+            let Some(soa) = negative_soa(response) else {
+                return;
+            };
+            let ttl = response
+                .answers
+                .iter()
+                .map(|rec| rec.ttl())
+                .fold(soa.ttl(), u32::min);
+
+            (ttl, response.answers.clone(), vec![soa], Vec::new())
+        } else {
+            // The OPT pseudo-record describes the upstream hop, not the data
+            let resources: Vec<DnsRecord> = response
+                .resources
+                .iter()
+                .filter(|rec| rec.qtype() != QueryType::UNKNOWN(OPT_TYPE))
+                .cloned()
+                .collect();
+
+            let ttl = response
+                .answers
+                .iter()
+                .chain(&response.authorities)
+                .chain(&resources)
+                .map(|rec| rec.ttl())
+                .min()
+                .unwrap_or(0);
+
+            (ttl, response.answers.clone(), response.authorities.clone(), resources)
+        };
+
+        let ttl = ttl.min(MAX_TTL);
+        if ttl == 0 {
+            return;
+        }
+
+        let key = CacheKey::new(question);
+        let mut inner = self.inner.lock().unwrap();
+
+        inner.remove(&key);
+        while inner.entries.len() >= self.max_entries {
+            if !inner.evict_oldest() {
+                break;
+            }
+        }
+
+        inner.tick += 1;
+        let last_used = inner.tick;
+        inner.lru.insert(last_used, key.clone());
+        inner.entries.insert(
+            key,
+            CacheEntry {
+                rescode: response.header.rescode,
+                answers,
+                authorities,
+                resources,
+                inserted: Instant::now(),
+                ttl,
+                last_used,
+                hits: 0,
+                refreshing: false,
+                refresh_failed: None,
+            },
+        );
+    }
+}
+
+impl CacheEntry {
+    fn expiry(&self) -> Duration {
+        Duration::from_secs(self.ttl as u64)
+    }
+
+    fn may_refresh(&self) -> bool {
+        !self.refreshing && self.refresh_failed.map_or(true, |at| at.elapsed() >= REFRESH_RECHECK)
+    }
+
+    /// Rebuild a response with each record's TTL passed through `ttl`
+    fn to_packet(&self, question: &DnsQuestion, ttl: impl Fn(u32) -> u32) -> DnsPacket {
+        let records = |records: &[DnsRecord]| -> Vec<DnsRecord> {
+            records
+                .iter()
+                .map(|rec| {
+                    let mut rec = rec.clone();
+                    rec.set_ttl(ttl(rec.ttl()));
+                    rec
+                })
+                .collect()
+        };
+
+        let mut packet = DnsPacket::new();
+        packet.header.rescode = self.rescode;
+        packet.questions.push(question.clone());
+        packet.answers = records(&self.answers);
+        packet.authorities = records(&self.authorities);
+        packet.resources = records(&self.resources);
+
+        packet
+    }
+}
+
+impl CacheInner {
+    fn remove(&mut self, key: &CacheKey) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.lru.remove(&entry.last_used);
+        }
+    }
+
+    /// Mark an entry as the most recently used
+    fn touch(&mut self, key: &CacheKey) {
+        self.tick += 1;
+        let tick = self.tick;
+        if let Some(entry) = self.entries.get_mut(key) {
+            self.lru.remove(&entry.last_used);
+            entry.last_used = tick;
+            self.lru.insert(tick, key.clone());
+        }
+    }
+
+    fn evict_oldest(&mut self) -> bool {
+        match self.lru.pop_first() {
+            Some((_, key)) => {
+                self.entries.remove(&key);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// The SOA to cache and return with a negative answer, its TTL lowered to
+/// the negative caching TTL: the lesser of its own TTL and its MINIMUM
+/// field (RFC 2308 section 5)
+fn negative_soa(response: &DnsPacket) -> Option<DnsRecord> {
+    response.authorities.iter().find_map(|rec| match rec {
+        DnsRecord::SOA { minimum, ttl, .. } => {
+            let mut soa = rec.clone();
+            soa.set_ttl((*ttl).min(*minimum));
+            Some(soa)
+        }
+        _ => None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{a, soa};
+
+    fn cname(ttl: u32) -> DnsRecord {
+        DnsRecord::CNAME {
+            domain: "www.example.com".to_string(),
+            class: QueryClass::IN,
+            host: "gone.example.com".to_string(),
+            ttl,
+        }
+    }
+
+    fn cache() -> Cache {
+        Cache::new(100, Duration::ZERO, PrefetchOptions::default())
+    }
+
+    #[test]
+    fn nxdomain_behind_cname_keeps_the_chain() {
+        let question = DnsQuestion::new("www.example.com".to_string(), QueryType::A);
+        let mut response = DnsPacket::new();
+        response.header.rescode = ResultCode::NXDOMAIN;
+        response.answers.push(cname(300));
+        response.authorities.push(soa(3600, 60));
+
+        let cache = cache();
+        cache.insert(&question, &response);
+
+        let cached = cache.get(&question).unwrap();
+        assert_eq!(cached.header.rescode, ResultCode::NXDOMAIN);
+        assert_eq!(cached.answers, vec![cname(300)]);
+        assert_eq!(cached.authorities, vec![soa(60, 60)]);
+    }
+
+    #[test]
+    fn nodata_behind_cname_uses_the_negative_ttl() {
+        let question = DnsQuestion::new("www.example.com".to_string(), QueryType::AAAA);
+        let mut response = DnsPacket::new();
+        response.answers.push(cname(300));
+        response.authorities.push(soa(3600, 60));
+
+        let cache = cache();
+        cache.insert(&question, &response);
+
+        let inner = cache.inner.lock().unwrap();
+        let entry = &inner.entries[&CacheKey::new(&question)];
+        assert_eq!(entry.ttl, 60);
+        assert_eq!(entry.answers, vec![cname(300)]);
+        assert_eq!(entry.authorities, vec![soa(60, 60)]);
+    }
+
+    #[test]
+    fn positive_answer_is_served_with_its_records() {
+        let question = DnsQuestion::new("www.example.com".to_string(), QueryType::A);
+        let mut response = DnsPacket::new();
+        response.answers.push(a("www.example.com", [192, 0, 2, 1], 300));
+
+        let cache = cache();
+        cache.insert(&question, &response);
+
+        let cached = cache.get(&question).unwrap();
+        assert_eq!(cached.header.rescode, ResultCode::NOERROR);
+        assert_eq!(cached.answers, response.answers);
+    }
+
+    #[test]
+    fn one_refresh_at_a_time_and_none_right_after_a_failure() {
+        let question = DnsQuestion::new("www.example.com".to_string(), QueryType::A);
+        let mut response = DnsPacket::new();
+        response.answers.push(a("www.example.com", [192, 0, 2, 1], 300));
+
+        let cache = cache();
+        cache.insert(&question, &response);
+
+        assert!(cache.claim_refresh(&question));
+        assert!(!cache.claim_refresh(&question));
+
+        cache.finish_refresh(&question, true);
+        assert!(!cache.claim_refresh(&question));
+
+        cache.insert(&question, &response);
+        assert!(cache.claim_refresh(&question));
+    }
+}
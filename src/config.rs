@@ -0,0 +1,180 @@
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+use std::str::FromStr;
+use std::time::Duration;
+
+use anyhow::{Result, anyhow};
+
+use crate::{
+    cache::PrefetchOptions, forward::ForwardRule, pool::Strategy, recursive::ROOT_HINTS,
+    upstream::UpstreamOptions, zone::Zone,
+};
+
+pub const USAGE: &str = "Usage: ./your_server [--resolver <ip:port>[,<ip:port>...] | \
+--recursive [--root-hints <ip:port>[,<ip:port>...]] [--ns-port <port>] [--no-qname-minimisation]] \
+[--zone [<origin>=]<zone file>]... \
+[--forward [*.]<zone>=<ip:port>[,<ip:port>...]]... \
+[--strategy failover|round-robin|random|fastest] \
+[--timeout-ms <ms>] [--retries <n>] [--backoff-ms <ms>] [--cache-size <entries>] \
+[--stale-secs <s>] [--stale-answer-timeout-ms <ms>] \
+[--prefetch-percent <n>] [--prefetch-min-hits <n>]";
+
+/// Default number of cached responses, 0 turns the cache off
+const DEFAULT_CACHE_SIZE: usize = 10000;
+
+/// How long a stale answer may wait on upstream before it is served anyway
+/// (the client response timer of RFC 8767 section 5)
+const DEFAULT_STALE_ANSWER_TIMEOUT: Duration = Duration::from_millis(1800);
+
+/// Server settings taken from the command line
+#[derive(Clone, Debug)]
+pub struct Config {
+    pub resolvers: Vec<SocketAddr>,
+    /// Resolve from the root instead of forwarding to `resolvers`
+    pub recursive: bool,
+    pub root_hints: Vec<SocketAddr>,
+    pub ns_port: u16,
+    /// Reveal only the next label of a name to each authority (RFC 9156)
+    pub qname_minimisation: bool,
+    /// Zones answered authoritatively instead of being looked up
+    pub zones: Vec<Zone>,
+    pub forwards: Vec<ForwardRule>,
+    pub strategy: Strategy,
+    pub upstream: UpstreamOptions,
+    pub cache_size: usize,
+    /// How long past expiry cached answers may be served stale, 0 for never
+    pub stale_window: Duration,
+    pub stale_answer_timeout: Duration,
+    pub prefetch: PrefetchOptions,
+}
+
+impl Config {
+    /// Parse the arguments following the program name
+    pub fn from_args(args: &[String]) -> Result<Config> {
+        let mut resolvers = Vec::new();
+        let mut recursive = false;
+        let mut root_hints = Vec::new();
+        let mut ns_port = 53;
+        let mut qname_minimisation = true;
+        let mut zones = Vec::new();
+        let mut forwards = Vec::new();
+        let mut strategy = Strategy::Failover;
+        let mut upstream = UpstreamOptions::default();
+        let mut cache_size = DEFAULT_CACHE_SIZE;
+        let mut stale_window = Duration::ZERO;
+        let mut stale_answer_timeout = DEFAULT_STALE_ANSWER_TIMEOUT;
+        let mut prefetch = PrefetchOptions::default();
+
+        let mut args = args.iter();
+        while let Some(flag) = args.next() {
+            // Flags that take no value
+            match flag.as_str() {
+                "--recursive" => {
+                    recursive = true;
+                    continue;
+                }
+                "--no-qname-minimisation" => {
+                    qname_minimisation = false;
+                    continue;
+                }
+                _ => {}
+            }
+
+            let value = args
+                .next()
+                .ok_or_else(|| anyhow!("Missing value for {}", flag))?;
+
+            match flag.as_str() {
+                // May be repeated, or given a comma separated list
+                "--resolver" => resolvers.extend(parse_addrs(value)?),
+                "--root-hints" => root_hints.extend(parse_addrs(value)?),
+                "--ns-port" => ns_port = parse_num(flag, value)?,
+                "--zone" => {
+                    let (origin, path) = match value.split_once('=') {
+                        Some((origin, path)) => (Some(origin), path),
+                        None => (None, value.as_str()),
+                    };
+                    zones.push(Zone::load(Path::new(path), origin)?);
+                }
+                "--forward" => forwards.push(value.parse()?),
+                "--strategy" => strategy = value.parse()?,
+                "--timeout-ms" => upstream.timeout = Duration::from_millis(parse_num(flag, value)?),
+                "--retries" => upstream.retries = parse_num(flag, value)?,
+                "--backoff-ms" => upstream.backoff = Duration::from_millis(parse_num(flag, value)?),
+                "--cache-size" => cache_size = parse_num(flag, value)?,
+                "--stale-secs" => stale_window = Duration::from_secs(parse_num(flag, value)?),
+                "--stale-answer-timeout-ms" => {
+                    stale_answer_timeout = Duration::from_millis(parse_num(flag, value)?)
+                }
+                "--prefetch-percent" => prefetch.percent = parse_num::<u32>(flag, value)?.min(100),
+                "--prefetch-min-hits" => prefetch.min_hits = parse_num(flag, value)?,
+                _ => return Err(anyhow!("Unknown option: {}", flag)),
+            }
+        }
+
+        if resolvers.is_empty() && !recursive && zones.is_empty() {
+            return Err(anyhow!("One of --resolver, --recursive or --zone is required"));
+        }
+        if recursive && !resolvers.is_empty() {
+            return Err(anyhow!("--resolver and --recursive can't be used together"));
+        }
+
+        if root_hints.is_empty() {
+            root_hints = ROOT_HINTS
+                .iter()
+                .map(|&ip| SocketAddr::new(IpAddr::V4(ip), 53))
+                .collect();
+        }
+
+        Ok(Config {
+            resolvers,
+            recursive,
+            root_hints,
+            ns_port,
+            qname_minimisation,
+            zones,
+            forwards,
+            strategy,
+            upstream,
+            cache_size,
+            stale_window,
+            stale_answer_timeout,
+            prefetch,
+        })
+    }
+}
+
+/// A comma separated list of socket addresses
+fn parse_addrs(value: &str) -> Result<Vec<SocketAddr>> {
+    value
+        .split(',')
+        .map(|addr| {
+            addr.parse::<SocketAddr>()
+                .map_err(|_| anyhow!("Invalid address: {}", addr))
+        })
+        .collect()
+}
+
+/// A number of whatever type the option needs, rejecting values that don't
+/// fit rather than wrapping them
+fn parse_num<T: FromStr>(flag: &str, value: &str) -> Result<T> {
+    value
+        .parse::<T>()
+        .map_err(|_| anyhow!("Invalid value for {}: {}", flag, value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn from_args(args: &[&str]) -> Result<Config> {
+        Config::from_args(&args.iter().map(|arg| arg.to_string()).collect::<Vec<_>>())
+    }
+
+    #[test]
+    fn resolver_and_recursive_are_exclusive() {
+        assert!(from_args(&["--resolver", "127.0.0.1:53"]).is_ok());
+        assert!(from_args(&["--recursive"]).is_ok());
+        assert!(from_args(&["--recursive", "--resolver", "127.0.0.1:53"]).is_err());
+    }
+}
@@ -0,0 +1,99 @@
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use anyhow::{Result, anyhow};
+
+use crate::{
+    name::{is_subdomain, label_count, normalize},
+    pool::{ResolverPool, Strategy},
+    upstream::UpstreamOptions,
+};
+
+/// Send questions under `zone` to their own resolvers. Written on the
+/// command line as `corp.example.=10.0.0.53:53,10.0.0.54:53`, or with a
+/// `*.` prefix (`*.consul.=...`) to match only names strictly below the zone.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ForwardRule {
+    pub zone: String,
+    pub subdomains_only: bool,
+    pub resolvers: Vec<SocketAddr>,
+}
+
+impl FromStr for ForwardRule {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<ForwardRule> {
+        let (zone, resolvers) = s
+            .split_once('=')
+            .ok_or_else(|| anyhow!("Forwarding rule must look like <zone>=<ip:port>: {}", s))?;
+
+        let (zone, subdomains_only) = match zone.strip_prefix("*.") {
+            Some(zone) => (zone, true),
+            None => (zone, false),
+        };
+
+        let resolvers = resolvers
+            .split(',')
+            .map(|addr| {
+                addr.parse::<SocketAddr>()
+                    .map_err(|_| anyhow!("Invalid resolver address: {}", addr))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(ForwardRule {
+            zone: normalize(zone),
+            subdomains_only,
+            resolvers,
+        })
+    }
+}
+
+impl ForwardRule {
+    /// How many labels of `name` this rule matches, if it applies at all
+    fn match_len(&self, name: &str) -> Option<usize> {
+        if self.subdomains_only && name == self.zone {
+            return None;
+        }
+
+        is_subdomain(name, &self.zone).then(|| label_count(&self.zone))
+    }
+}
+
+/// Picks the resolver pool for a question: the rule with the longest
+/// matching zone suffix, or the default resolvers when none match. Without
+/// default resolvers (recursive mode) unmatched names get no pool at all.
+pub struct Forwarder {
+    rules: Vec<(ForwardRule, Arc<ResolverPool>)>,
+    default: Option<Arc<ResolverPool>>,
+}
+
+impl Forwarder {
+    pub fn new(default: &[SocketAddr], rules: &[ForwardRule], strategy: Strategy) -> Forwarder {
+        Forwarder {
+            rules: rules
+                .iter()
+                .map(|rule| (rule.clone(), Arc::new(ResolverPool::new(&rule.resolvers, strategy))))
+                .collect(),
+            default: (!default.is_empty()).then(|| Arc::new(ResolverPool::new(default, strategy))),
+        }
+    }
+
+    pub fn pool_for(&self, name: &str) -> Option<&ResolverPool> {
+        let name = normalize(name);
+
+        self.rules
+            .iter()
+            .filter_map(|(rule, pool)| rule.match_len(&name).map(|len| (len, pool)))
+            .max_by_key(|(len, _)| *len)
+            .map(|(_, pool)| pool)
+            .or(self.default.as_ref())
+            .map(|pool| pool.as_ref())
+    }
+
+    pub fn spawn_health_checks(&self, options: &UpstreamOptions) {
+        for pool in self.rules.iter().map(|(_, pool)| pool).chain(&self.default) {
+            pool.clone().spawn_health_checks(options.clone());
+        }
+    }
+}
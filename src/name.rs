@@ -0,0 +1,44 @@
+/// Lowercase a name and drop any trailing dot, matching how `read_qname`
+/// hands names to us
+pub fn normalize(name: &str) -> String {
+    name.trim_end_matches('.').to_lowercase()
+}
+
+/// The number of labels in `name`, the root having none
+pub fn label_count(name: &str) -> usize {
+    name.split('.').filter(|l| !l.is_empty()).count()
+}
+
+/// `name` with its first label removed, the root being its own parent
+pub fn parent(name: &str) -> &str {
+    name.split_once('.').map_or("", |(_, parent)| parent)
+}
+
+/// Whether `name` is `zone` or somewhere below it
+pub fn is_subdomain(name: &str, zone: &str) -> bool {
+    let name = normalize(name);
+    let zone = normalize(zone);
+
+    zone.is_empty() || name == zone || name.ends_with(&format!(".{}", zone))
+}
+
+/// Whether `child` is strictly below `zone`
+pub fn is_below(child: &str, zone: &str) -> bool {
+    label_count(child) > label_count(zone) && is_subdomain(child, zone)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn subdomains_match_whole_labels_only() {
+        assert!(is_subdomain("www.Example.com.", "example.com"));
+        assert!(is_subdomain("example.com", "EXAMPLE.COM."));
+        assert!(is_subdomain("example.com", ""));
+        assert!(!is_subdomain("badexample.com", "example.com"));
+
+        assert!(is_below("www.example.com", "example.com"));
+        assert!(!is_below("example.com", "example.com."));
+    }
+}
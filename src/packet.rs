@@ -1,6 +1,12 @@
-use crate::{byte_packet_buffer::BytePacketBuffer, header::DnsHeader, query::{DnsQuestion, QueryType}, record::DnsRecord};
+use crate::{byte_packet_buffer::{BufferFull, BytePacketBuffer, TCP_MAX_SIZE, UDP_MAX_SIZE}, header::DnsHeader, name::{is_subdomain, normalize}, query::{DnsQuestion, QueryClass, QueryType}, record::DnsRecord};
 use anyhow::Result;
 
+/// Record type of the EDNS(0) OPT pseudo-record (RFC 6891)
+pub const OPT_TYPE: u16 = 41;
+
+/// UDP payload size we advertise and accept from EDNS clients
+pub const EDNS_MAX_SIZE: usize = 4096;
+
 
 #[derive(Clone, Debug)]
 pub struct DnsPacket {
@@ -48,27 +54,235 @@ impl DnsPacket {
         Ok(result)
     }
 
+    /// The EDNS OPT pseudo-record in the additional section, if any
+    pub fn edns(&self) -> Option<&DnsRecord> {
+        self.resources
+            .iter()
+            .find(|rec| rec.qtype() == QueryType::UNKNOWN(OPT_TYPE))
+    }
+
+    /// Largest UDP response the sender of this packet accepts: the payload
+    /// size from its OPT record, but never less than the classic 512 bytes
+    pub fn max_udp_size(&self) -> usize {
+        match self.edns() {
+            Some(opt) => (opt.class().to_num() as usize).max(UDP_MAX_SIZE),
+            None => UDP_MAX_SIZE,
+        }
+    }
+
+    /// Add an OPT record advertising our own UDP payload size
+    pub fn add_edns(&mut self) {
+        self.resources.push(DnsRecord::UNKNOWN {
+            domain: String::new(),
+            class: QueryClass::from_num(EDNS_MAX_SIZE as u16),
+            qtype: OPT_TYPE,
+            data: Vec::new(),
+            ttl: 0,
+        });
+    }
+
+    /// Write the packet, fitting it into the buffer's maximum size. RRsets
+    /// that don't fit are dropped whole from the end; losing any answer or
+    /// authority data sets the TC bit so the client retries over TCP, while
+    /// additional data is optional and just omitted (RFC 2181 section 9).
+    /// The exceptions are the OPT record, which room is kept for so it goes
+    /// out even in a truncated reply (RFC 6891 section 7), and the glue of a
+    /// referral for in-domain nameservers, which sets TC like answer data
+    /// when it doesn't fit (RFC 9471 section 3).
     pub fn write(&mut self, buffer: &mut BytePacketBuffer) -> Result<()> {
-        self.header.questions = self.questions.len() as u16;
-        self.header.answers = self.answers.len() as u16;
-        self.header.authoritative_entries = self.authorities.len() as u16;
-        self.header.resource_entries = self.resources.len() as u16;
+        let start = buffer.pos();
+        let referral = self.is_referral();
+
+        let (opt, mut resources): (Vec<DnsRecord>, Vec<DnsRecord>) = std::mem::take(&mut self.resources)
+            .into_iter()
+            .partition(|rec| rec.qtype() == QueryType::UNKNOWN(OPT_TYPE));
+        let mut scratch = BytePacketBuffer::with_max_size(TCP_MAX_SIZE);
+        for rec in &opt {
+            rec.write(&mut scratch)?;
+        }
+        let opt_len = scratch.pos();
+        buffer.reserve(opt_len);
 
         self.header.write(buffer)?;
 
         for question in &self.questions {
             question.write(buffer)?;
         }
-        for rec in &self.answers {
-            rec.write(buffer)?;
+
+        let mut truncated = Self::write_section(buffer, &mut self.answers, true)?;
+        if truncated {
+            self.authorities.clear();
+        } else {
+            truncated = Self::write_section(buffer, &mut self.authorities, true)?;
         }
-        for rec in &self.authorities {
-            rec.write(buffer)?;
+        if truncated {
+            resources.clear();
+        } else {
+            let (mut glue, mut rest): (Vec<DnsRecord>, Vec<DnsRecord>) = if referral {
+                resources
+                    .into_iter()
+                    .partition(|rec| Self::is_required_glue(rec, &self.authorities))
+            } else {
+                (Vec::new(), resources)
+            };
+
+            truncated = Self::write_section(buffer, &mut glue, true)?;
+            if truncated {
+                rest.clear();
+            } else {
+                Self::write_section(buffer, &mut rest, false)?;
+            }
+            resources = glue;
+            resources.extend(rest);
         }
-        for rec in &self.resources {
+
+        buffer.release(opt_len);
+        for rec in &opt {
             rec.write(buffer)?;
         }
+        resources.extend(opt);
+        self.resources = resources;
+
+        if truncated {
+            self.header.truncated_message = true;
+        }
+        self.header.questions = self.questions.len() as u16;
+        self.header.answers = self.answers.len() as u16;
+        self.header.authoritative_entries = self.authorities.len() as u16;
+        self.header.resource_entries = self.resources.len() as u16;
+
+        // Go back and rewrite the header with the final counts
+        let end = buffer.pos();
+        buffer.seek(start)?;
+        self.header.write(buffer)?;
+        buffer.seek(end)?;
 
         Ok(())
     }
-}
\ No newline at end of file
+
+    /// Whether this is a referral: nameservers in the authority section, and
+    /// neither an answer to the question nor an SOA saying there is none
+    fn is_referral(&self) -> bool {
+        let answered = self
+            .questions
+            .iter()
+            .any(|question| self.answers.iter().any(|rec| rec.qtype() == question.qtype));
+        let has = |qtype| self.authorities.iter().any(|rec| rec.qtype() == qtype);
+
+        !answered && has(QueryType::NS) && !has(QueryType::SOA)
+    }
+
+    /// Whether `rec` is an address of a nameserver named below the zone it
+    /// serves, which a resolver can't look up without it (RFC 9471 section 2.1)
+    fn is_required_glue(rec: &DnsRecord, authorities: &[DnsRecord]) -> bool {
+        matches!(rec.qtype(), QueryType::A | QueryType::AAAA)
+            && authorities.iter().any(|ns| match ns {
+                DnsRecord::NS { domain, host, .. } => {
+                    normalize(host) == normalize(rec.domain()) && is_subdomain(host, domain)
+                }
+                _ => false,
+            })
+    }
+
+    /// Write a section RRset by RRset, dropping any set that doesn't fit.
+    /// With `stop_on_overflow` everything after the first such set goes too.
+    /// Returns whether anything was dropped.
+    fn write_section(
+        buffer: &mut BytePacketBuffer,
+        records: &mut Vec<DnsRecord>,
+        stop_on_overflow: bool,
+    ) -> Result<bool> {
+        let mut written = Vec::with_capacity(records.len());
+        let mut dropped = false;
+
+        let mut rest = &records[..];
+        while let Some(first) = rest.first() {
+            let len = rest.iter().take_while(|rec| rec.same_rrset(first)).count();
+            let (rrset, remaining) = rest.split_at(len);
+            rest = remaining;
+
+            let pos = buffer.pos();
+            match rrset.iter().try_for_each(|rec| rec.write(buffer).map(|_| ())) {
+                Ok(()) => written.extend_from_slice(rrset),
+                Err(e) if e.is::<BufferFull>() => {
+                    buffer.truncate(pos);
+                    dropped = true;
+                    if stop_on_overflow {
+                        break;
+                    }
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
+        *records = written;
+
+        Ok(dropped)
+    }
+}
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+    use crate::testing::{a, ns};
+
+    #[test]
+    fn truncated_reply_keeps_opt() {
+        let mut packet = DnsPacket::new();
+        packet.questions.push(DnsQuestion::new("example.com".to_string(), QueryType::A));
+        for i in 0..60 {
+            packet.answers.push(DnsRecord::new_a(
+                format!("host{}.example.com", i),
+                Ipv4Addr::new(192, 0, 2, i),
+                60,
+            ));
+        }
+        packet.add_edns();
+
+        let mut buffer = BytePacketBuffer::with_max_size(UDP_MAX_SIZE);
+        packet.write(&mut buffer).unwrap();
+        assert!(buffer.pos() <= UDP_MAX_SIZE);
+
+        let mut buffer = BytePacketBuffer::from_bytes(buffer.as_slice());
+        let parsed = DnsPacket::from_buffer(&mut buffer).unwrap();
+        assert!(parsed.header.truncated_message);
+        assert!(parsed.answers.len() < 60);
+        assert!(parsed.edns().is_some());
+    }
+
+    fn referral(hosts: u8) -> DnsPacket {
+        let mut packet = DnsPacket::new();
+        packet.questions.push(DnsQuestion::new("www.sub.example.com".to_string(), QueryType::A));
+        for i in 0..hosts {
+            // Long names leave the authority section room but not the glue
+            let host = format!("ns{}-{}.sub.example.com", i, "x".repeat(40));
+            packet.authorities.push(ns("sub.example.com", &host));
+            packet.resources.push(a(&host, [192, 0, 2, i], 300));
+        }
+        packet
+    }
+
+    #[test]
+    fn referral_glue_that_does_not_fit_sets_tc() {
+        let mut buffer = BytePacketBuffer::with_max_size(UDP_MAX_SIZE);
+        referral(8).write(&mut buffer).unwrap();
+
+        let mut buffer = BytePacketBuffer::from_bytes(buffer.as_slice());
+        let parsed = DnsPacket::from_buffer(&mut buffer).unwrap();
+        assert!(parsed.header.truncated_message);
+        assert_eq!(parsed.authorities.len(), 8);
+        assert!(parsed.resources.len() < 8);
+    }
+
+    #[test]
+    fn referral_glue_that_fits_does_not_set_tc() {
+        let mut buffer = BytePacketBuffer::with_max_size(UDP_MAX_SIZE);
+        referral(2).write(&mut buffer).unwrap();
+
+        let mut buffer = BytePacketBuffer::from_bytes(buffer.as_slice());
+        let parsed = DnsPacket::from_buffer(&mut buffer).unwrap();
+        assert!(!parsed.header.truncated_message);
+        assert_eq!(parsed.resources.len(), 2);
+    }
+}
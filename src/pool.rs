@@ -0,0 +1,228 @@
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::{Result, anyhow};
+use rand::seq::SliceRandom;
+
+use crate::{
+    header::ResultCode,
+    packet::DnsPacket,
+    query::{DnsQuestion, QueryType},
+    upstream::{self, UpstreamOptions},
+};
+
+/// Consecutive failures after which a resolver is taken out of rotation
+const MAX_FAILURES: u32 = 3;
+
+/// How often sidelined resolvers are probed to see if they are back
+const PROBE_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Weight given to the newest sample in the latency moving average
+const LATENCY_WEIGHT: f64 = 0.3;
+
+/// The order in which upstream resolvers are tried
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Strategy {
+    /// Always in the configured order, moving on only when one fails
+    Failover,
+    /// Start from the next resolver in turn for each query
+    RoundRobin,
+    /// A fresh random order for each query
+    Random,
+    /// Lowest observed response time first
+    Fastest,
+}
+
+impl FromStr for Strategy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Strategy> {
+        match s {
+            "failover" => Ok(Strategy::Failover),
+            "round-robin" => Ok(Strategy::RoundRobin),
+            "random" => Ok(Strategy::Random),
+            "fastest" => Ok(Strategy::Fastest),
+            _ => Err(anyhow!("Unknown strategy: {}", s)),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct ResolverState {
+    addr: SocketAddr,
+    consecutive_failures: u32,
+    sidelined: bool,
+    latency: Option<Duration>,
+}
+
+/// A set of upstream resolvers with per-resolver health tracking
+#[derive(Debug)]
+pub struct ResolverPool {
+    resolvers: Mutex<Vec<ResolverState>>,
+    strategy: Strategy,
+    next: AtomicUsize,
+}
+
+impl ResolverPool {
+    pub fn new(addrs: &[SocketAddr], strategy: Strategy) -> ResolverPool {
+        let resolvers = addrs
+            .iter()
+            .map(|&addr| ResolverState {
+                addr,
+                consecutive_failures: 0,
+                sidelined: false,
+                latency: None,
+            })
+            .collect();
+
+        ResolverPool {
+            resolvers: Mutex::new(resolvers),
+            strategy,
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    /// Ask the question of each resolver in strategy order until one gives a
+    /// usable answer. Each resolver gets a single attempt per pass, so a dead
+    /// one costs one timeout before the next is tried; `options.retries`
+    /// adds further passes over those that haven't answered, with the same
+    /// backoff between passes as between attempts on one resolver. SERVFAIL
+    /// and REFUSED move on to the next resolver but say nothing about the
+    /// resolver's health, since they are usually down to the domain.
+    pub async fn lookup(&self, question: &DnsQuestion, options: &UpstreamOptions) -> Result<DnsPacket> {
+        let single_attempt = UpstreamOptions {
+            retries: 0,
+            ..options.clone()
+        };
+        let mut answered = Vec::new();
+        let mut delay = options.backoff;
+
+        for pass in 0..=options.retries {
+            let candidates: Vec<SocketAddr> = self
+                .candidates()
+                .into_iter()
+                .filter(|addr| !answered.contains(addr))
+                .collect();
+            if candidates.is_empty() {
+                break;
+            }
+            if pass > 0 {
+                tokio::time::sleep(delay).await;
+                delay = options.next_backoff(delay);
+            }
+
+            for addr in candidates {
+                println!("Forwarding question: {:#?} to resolver: {}", question, addr);
+
+                let start = Instant::now();
+                match upstream::lookup(question, addr, &single_attempt).await {
+                    Ok(response) if is_usable(&response) => {
+                        self.record_success(addr, start.elapsed());
+                        return Ok(response);
+                    }
+                    Ok(response) => {
+                        println!("Resolver {} answered {:?}", addr, response.header.rescode);
+                        self.record_success(addr, start.elapsed());
+                        answered.push(addr);
+                    }
+                    Err(e) => {
+                        println!("Resolver {} failed: {}", addr, e);
+                        self.record_failure(addr);
+                    }
+                }
+            }
+        }
+
+        Err(anyhow!("No resolver could answer {}", question.name))
+    }
+
+    /// Resolvers to try, healthy ones first in strategy order. Sidelined
+    /// resolvers come last so that something is still tried when all are down.
+    fn candidates(&self) -> Vec<SocketAddr> {
+        let resolvers = self.resolvers.lock().unwrap();
+
+        let mut healthy: Vec<&ResolverState> = resolvers.iter().filter(|r| !r.sidelined).collect();
+        match self.strategy {
+            Strategy::Failover => {}
+            Strategy::RoundRobin => {
+                if !healthy.is_empty() {
+                    let start = self.next.fetch_add(1, Ordering::Relaxed) % healthy.len();
+                    healthy.rotate_left(start);
+                }
+            }
+            Strategy::Random => healthy.shuffle(&mut rand::thread_rng()),
+            // Resolvers without a measurement yet sort first so they get one
+            Strategy::Fastest => healthy.sort_by_key(|r| r.latency.unwrap_or(Duration::ZERO)),
+        }
+
+        healthy
+            .into_iter()
+            .chain(resolvers.iter().filter(|r| r.sidelined))
+            .map(|r| r.addr)
+            .collect()
+    }
+
+    fn record_success(&self, addr: SocketAddr, elapsed: Duration) {
+        let mut resolvers = self.resolvers.lock().unwrap();
+        if let Some(resolver) = resolvers.iter_mut().find(|r| r.addr == addr) {
+            if resolver.sidelined {
+                println!("Resolver {} is back in rotation", addr);
+            }
+            resolver.consecutive_failures = 0;
+            resolver.sidelined = false;
+            resolver.latency = Some(match resolver.latency {
+                Some(latency) => latency.mul_f64(1.0 - LATENCY_WEIGHT) + elapsed.mul_f64(LATENCY_WEIGHT),
+                None => elapsed,
+            });
+        }
+    }
+
+    fn record_failure(&self, addr: SocketAddr) {
+        let mut resolvers = self.resolvers.lock().unwrap();
+        if let Some(resolver) = resolvers.iter_mut().find(|r| r.addr == addr) {
+            resolver.consecutive_failures += 1;
+            if resolver.consecutive_failures >= MAX_FAILURES && !resolver.sidelined {
+                println!("Sidelining resolver {} after {} failures", addr, resolver.consecutive_failures);
+                resolver.sidelined = true;
+            }
+        }
+    }
+
+    /// Periodically probe sidelined resolvers with a root NS query and put
+    /// them back in rotation once they answer again
+    pub fn spawn_health_checks(self: Arc<Self>, options: UpstreamOptions) {
+        let options = UpstreamOptions {
+            retries: 0,
+            ..options
+        };
+
+        tokio::spawn(async move {
+            let probe = DnsQuestion::new(String::new(), QueryType::NS);
+            loop {
+                tokio::time::sleep(PROBE_INTERVAL).await;
+
+                let sidelined: Vec<SocketAddr> = {
+                    let resolvers = self.resolvers.lock().unwrap();
+                    resolvers.iter().filter(|r| r.sidelined).map(|r| r.addr).collect()
+                };
+
+                for addr in sidelined {
+                    let start = Instant::now();
+                    match upstream::lookup(&probe, addr, &options).await {
+                        // Any reply at all shows it is reachable again
+                        Ok(_) => self.record_success(addr, start.elapsed()),
+                        Err(_) => println!("Resolver {} still unavailable", addr),
+                    }
+                }
+            }
+        });
+    }
+}
+
+/// Whether a response is worth relaying rather than trying another resolver
+fn is_usable(response: &DnsPacket) -> bool {
+    !matches!(response.header.rescode, ResultCode::SERVFAIL | ResultCode::REFUSED)
+}
@@ -1,28 +1,192 @@
-use std::net::Ipv4Addr;
-use anyhow::Result;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use anyhow::{Result, anyhow};
 
-use crate::{byte_packet_buffer::BytePacketBuffer, query::QueryType};
+use crate::{byte_packet_buffer::BytePacketBuffer, query::{QueryClass, QueryType}};
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
 #[allow(dead_code)]
 pub enum DnsRecord {
     UNKNOWN {
         domain: String,
+        class: QueryClass,
         qtype: u16,
-        data_len: u16,
+        data: Vec<u8>,
         ttl: u32,
     }, // 0
     A {
         domain: String,
+        class: QueryClass,
         addr: Ipv4Addr,
         ttl: u32,
     }, // 1
+    NS {
+        domain: String,
+        class: QueryClass,
+        host: String,
+        ttl: u32,
+    }, // 2
+    CNAME {
+        domain: String,
+        class: QueryClass,
+        host: String,
+        ttl: u32,
+    }, // 5
+    SOA {
+        domain: String,
+        class: QueryClass,
+        mname: String,
+        rname: String,
+        serial: u32,
+        refresh: u32,
+        retry: u32,
+        expire: u32,
+        minimum: u32,
+        ttl: u32,
+    }, // 6
+    PTR {
+        domain: String,
+        class: QueryClass,
+        host: String,
+        ttl: u32,
+    }, // 12
+    MX {
+        domain: String,
+        class: QueryClass,
+        priority: u16,
+        host: String,
+        ttl: u32,
+    }, // 15
+    TXT {
+        domain: String,
+        class: QueryClass,
+        strings: Vec<Vec<u8>>,
+        ttl: u32,
+    }, // 16
+    AAAA {
+        domain: String,
+        class: QueryClass,
+        addr: Ipv6Addr,
+        ttl: u32,
+    }, // 28
+    SRV {
+        domain: String,
+        class: QueryClass,
+        priority: u16,
+        weight: u16,
+        port: u16,
+        target: String,
+        ttl: u32,
+    }, // 33
 }
 
 impl DnsRecord {
-    
+
+    #[allow(dead_code)]
     pub fn new_a(domain: String, addr: Ipv4Addr, ttl: u32)-> Self{
-        DnsRecord::A { domain: domain, addr: addr, ttl: ttl }
+        DnsRecord::A {
+            domain,
+            class: QueryClass::IN,
+            addr,
+            ttl,
+        }
+    }
+
+    /// Owner name of the record
+    pub fn domain(&self) -> &str {
+        match self {
+            DnsRecord::UNKNOWN { domain, .. }
+            | DnsRecord::A { domain, .. }
+            | DnsRecord::NS { domain, .. }
+            | DnsRecord::CNAME { domain, .. }
+            | DnsRecord::SOA { domain, .. }
+            | DnsRecord::PTR { domain, .. }
+            | DnsRecord::MX { domain, .. }
+            | DnsRecord::TXT { domain, .. }
+            | DnsRecord::AAAA { domain, .. }
+            | DnsRecord::SRV { domain, .. } => domain,
+        }
+    }
+
+    pub fn qtype(&self) -> QueryType {
+        match self {
+            DnsRecord::UNKNOWN { qtype, .. } => QueryType::from_num(*qtype),
+            DnsRecord::A { .. } => QueryType::A,
+            DnsRecord::NS { .. } => QueryType::NS,
+            DnsRecord::CNAME { .. } => QueryType::CNAME,
+            DnsRecord::SOA { .. } => QueryType::SOA,
+            DnsRecord::PTR { .. } => QueryType::PTR,
+            DnsRecord::MX { .. } => QueryType::MX,
+            DnsRecord::TXT { .. } => QueryType::TXT,
+            DnsRecord::AAAA { .. } => QueryType::AAAA,
+            DnsRecord::SRV { .. } => QueryType::SRV,
+        }
+    }
+
+    pub fn class(&self) -> QueryClass {
+        match self {
+            DnsRecord::UNKNOWN { class, .. }
+            | DnsRecord::A { class, .. }
+            | DnsRecord::NS { class, .. }
+            | DnsRecord::CNAME { class, .. }
+            | DnsRecord::SOA { class, .. }
+            | DnsRecord::PTR { class, .. }
+            | DnsRecord::MX { class, .. }
+            | DnsRecord::TXT { class, .. }
+            | DnsRecord::AAAA { class, .. }
+            | DnsRecord::SRV { class, .. } => *class,
+        }
+    }
+
+    pub fn ttl(&self) -> u32 {
+        match self {
+            DnsRecord::UNKNOWN { ttl, .. }
+            | DnsRecord::A { ttl, .. }
+            | DnsRecord::NS { ttl, .. }
+            | DnsRecord::CNAME { ttl, .. }
+            | DnsRecord::SOA { ttl, .. }
+            | DnsRecord::PTR { ttl, .. }
+            | DnsRecord::MX { ttl, .. }
+            | DnsRecord::TXT { ttl, .. }
+            | DnsRecord::AAAA { ttl, .. }
+            | DnsRecord::SRV { ttl, .. } => *ttl,
+        }
+    }
+
+    pub fn set_ttl(&mut self, new_ttl: u32) {
+        match self {
+            DnsRecord::UNKNOWN { ttl, .. }
+            | DnsRecord::A { ttl, .. }
+            | DnsRecord::NS { ttl, .. }
+            | DnsRecord::CNAME { ttl, .. }
+            | DnsRecord::SOA { ttl, .. }
+            | DnsRecord::PTR { ttl, .. }
+            | DnsRecord::MX { ttl, .. }
+            | DnsRecord::TXT { ttl, .. }
+            | DnsRecord::AAAA { ttl, .. }
+            | DnsRecord::SRV { ttl, .. } => *ttl = new_ttl,
+        }
+    }
+
+    pub fn set_domain(&mut self, new_domain: String) {
+        match self {
+            DnsRecord::UNKNOWN { domain, .. }
+            | DnsRecord::A { domain, .. }
+            | DnsRecord::NS { domain, .. }
+            | DnsRecord::CNAME { domain, .. }
+            | DnsRecord::SOA { domain, .. }
+            | DnsRecord::PTR { domain, .. }
+            | DnsRecord::MX { domain, .. }
+            | DnsRecord::TXT { domain, .. }
+            | DnsRecord::AAAA { domain, .. }
+            | DnsRecord::SRV { domain, .. } => *domain = new_domain,
+        }
+    }
+
+    /// Whether two records belong to the same RRset (RFC 2181 section 5)
+    pub fn same_rrset(&self, other: &DnsRecord) -> bool {
+        self.qtype() == other.qtype()
+            && self.class() == other.class()
+            && self.domain().eq_ignore_ascii_case(other.domain())
     }
 
     pub fn read(buffer: &mut BytePacketBuffer) -> Result<DnsRecord> {
@@ -31,37 +195,200 @@ impl DnsRecord {
 
         let qtype_num = buffer.read_u16()?;
         let qtype = QueryType::from_num(qtype_num);
-        let _ = buffer.read_u16()?;
+        let class = QueryClass::from_num(buffer.read_u16()?);
         let ttl = buffer.read_u32()?;
-        let data_len = buffer.read_u16()?;
+        let data_len = buffer.read_u16()? as usize;
+        let start = buffer.pos();
+        let end = start + data_len;
 
-        match qtype {
+        // Empty RDATA is legal for any type, e.g. in class ANY and NONE
+        // update prerequisites (RFC 2136 section 2.4), so keep it opaque
+        if data_len == 0 {
+            return Ok(DnsRecord::UNKNOWN {
+                domain,
+                class,
+                qtype: qtype_num,
+                data: Vec::new(),
+                ttl,
+            });
+        }
+
+        let record: Result<DnsRecord> = match qtype {
             QueryType::A => {
                 let raw_addr = buffer.read_u32()?;
                 let addr = Ipv4Addr::new(
                     ((raw_addr >> 24) & 0xFF) as u8,
                     ((raw_addr >> 16) & 0xFF) as u8,
                     ((raw_addr >> 8) & 0xFF) as u8,
-                    ((raw_addr >> 0) & 0xFF) as u8,
+                    (raw_addr & 0xFF) as u8,
                 );
 
                 Ok(DnsRecord::A {
-                    domain: domain,
-                    addr: addr,
-                    ttl: ttl,
+                    domain,
+                    class,
+                    addr,
+                    ttl,
+                })
+            }
+            QueryType::AAAA => {
+                let raw_addr1 = buffer.read_u32()?;
+                let raw_addr2 = buffer.read_u32()?;
+                let raw_addr3 = buffer.read_u32()?;
+                let raw_addr4 = buffer.read_u32()?;
+                let addr = Ipv6Addr::new(
+                    ((raw_addr1 >> 16) & 0xFFFF) as u16,
+                    (raw_addr1 & 0xFFFF) as u16,
+                    ((raw_addr2 >> 16) & 0xFFFF) as u16,
+                    (raw_addr2 & 0xFFFF) as u16,
+                    ((raw_addr3 >> 16) & 0xFFFF) as u16,
+                    (raw_addr3 & 0xFFFF) as u16,
+                    ((raw_addr4 >> 16) & 0xFFFF) as u16,
+                    (raw_addr4 & 0xFFFF) as u16,
+                );
+
+                Ok(DnsRecord::AAAA {
+                    domain,
+                    class,
+                    addr,
+                    ttl,
+                })
+            }
+            QueryType::NS => {
+                let mut host = String::new();
+                buffer.read_qname(&mut host)?;
+
+                Ok(DnsRecord::NS {
+                    domain,
+                    class,
+                    host,
+                    ttl,
+                })
+            }
+            QueryType::CNAME => {
+                let mut host = String::new();
+                buffer.read_qname(&mut host)?;
+
+                Ok(DnsRecord::CNAME {
+                    domain,
+                    class,
+                    host,
+                    ttl,
+                })
+            }
+            QueryType::PTR => {
+                let mut host = String::new();
+                buffer.read_qname(&mut host)?;
+
+                Ok(DnsRecord::PTR {
+                    domain,
+                    class,
+                    host,
+                    ttl,
+                })
+            }
+            QueryType::SOA => {
+                let mut mname = String::new();
+                buffer.read_qname(&mut mname)?;
+                let mut rname = String::new();
+                buffer.read_qname(&mut rname)?;
+
+                let serial = buffer.read_u32()?;
+                let refresh = buffer.read_u32()?;
+                let retry = buffer.read_u32()?;
+                let expire = buffer.read_u32()?;
+                let minimum = buffer.read_u32()?;
+
+                Ok(DnsRecord::SOA {
+                    domain,
+                    class,
+                    mname,
+                    rname,
+                    serial,
+                    refresh,
+                    retry,
+                    expire,
+                    minimum,
+                    ttl,
+                })
+            }
+            QueryType::MX => {
+                let priority = buffer.read_u16()?;
+                let mut host = String::new();
+                buffer.read_qname(&mut host)?;
+
+                Ok(DnsRecord::MX {
+                    domain,
+                    class,
+                    priority,
+                    host,
+                    ttl,
+                })
+            }
+            QueryType::TXT => {
+                // RDATA is one or more <character-string>s, each a length
+                // octet followed by that many bytes. Kept as raw bytes since
+                // TXT content is not guaranteed to be UTF-8.
+                let mut strings = Vec::new();
+                while buffer.pos() < end {
+                    let len = buffer.read_u8()? as usize;
+                    if buffer.pos() + len > end {
+                        return Err(anyhow!("TXT character-string overruns RDATA"));
+                    }
+                    strings.push(buffer.read_bytes(len)?);
+                }
+
+                Ok(DnsRecord::TXT {
+                    domain,
+                    class,
+                    strings,
+                    ttl,
+                })
+            }
+            QueryType::SRV => {
+                let priority = buffer.read_u16()?;
+                let weight = buffer.read_u16()?;
+                let port = buffer.read_u16()?;
+                let mut target = String::new();
+                buffer.read_qname(&mut target)?;
+
+                Ok(DnsRecord::SRV {
+                    domain,
+                    class,
+                    priority,
+                    weight,
+                    port,
+                    target,
+                    ttl,
                 })
             }
             QueryType::UNKNOWN(_) => {
-                buffer.step(data_len as usize)?;
+                // Keep the RDATA opaque (RFC 3597) so it can be relayed as-is
+                let data = buffer.read_bytes(data_len)?;
 
                 Ok(DnsRecord::UNKNOWN {
-                    domain: domain,
+                    domain,
+                    class,
                     qtype: qtype_num,
-                    data_len: data_len,
-                    ttl: ttl,
+                    data,
+                    ttl,
                 })
             }
+        };
+        let record = record?;
+
+        // Typed RDATA must fill RDLENGTH exactly, or every record after
+        // this one would be read from the wrong offset
+        if buffer.pos() != end {
+            return Err(anyhow!(
+                "{:?} RDATA is {} bytes but RDLENGTH says {}",
+                qtype,
+                buffer.pos() - start,
+                data_len
+            ));
         }
+        buffer.seek(end)?;
+
+        Ok(record)
     }
 
     pub fn write(&self, buffer: &mut BytePacketBuffer) -> Result<usize> {
@@ -70,12 +397,13 @@ impl DnsRecord {
         match *self {
             DnsRecord::A {
                 ref domain,
+                class,
                 ref addr,
                 ttl,
             } => {
                 buffer.write_qname(domain)?;
                 buffer.write_u16(QueryType::A.to_num())?;
-                buffer.write_u16(1)?;
+                buffer.write_u16(class.to_num())?;
                 buffer.write_u32(ttl)?;
                 buffer.write_u16(4)?;
 
@@ -85,11 +413,239 @@ impl DnsRecord {
                 buffer.write_u8(octets[2])?;
                 buffer.write_u8(octets[3])?;
             }
-            DnsRecord::UNKNOWN { .. } => {
-                println!("Skipping record: {:?}", self);
+            DnsRecord::AAAA {
+                ref domain,
+                class,
+                ref addr,
+                ttl,
+            } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::AAAA.to_num())?;
+                buffer.write_u16(class.to_num())?;
+                buffer.write_u32(ttl)?;
+                buffer.write_u16(16)?;
+
+                for octet in &addr.segments() {
+                    buffer.write_u16(*octet)?;
+                }
+            }
+            DnsRecord::NS {
+                ref domain,
+                class,
+                ref host,
+                ttl,
+            } => {
+                Self::write_name_record(buffer, domain, class, QueryType::NS, ttl, host)?;
+            }
+            DnsRecord::CNAME {
+                ref domain,
+                class,
+                ref host,
+                ttl,
+            } => {
+                Self::write_name_record(buffer, domain, class, QueryType::CNAME, ttl, host)?;
+            }
+            DnsRecord::PTR {
+                ref domain,
+                class,
+                ref host,
+                ttl,
+            } => {
+                Self::write_name_record(buffer, domain, class, QueryType::PTR, ttl, host)?;
+            }
+            DnsRecord::SOA {
+                ref domain,
+                class,
+                ref mname,
+                ref rname,
+                serial,
+                refresh,
+                retry,
+                expire,
+                minimum,
+                ttl,
+            } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::SOA.to_num())?;
+                buffer.write_u16(class.to_num())?;
+                buffer.write_u32(ttl)?;
+
+                let pos = buffer.pos();
+                buffer.write_u16(0)?;
+
+                buffer.write_qname(mname)?;
+                buffer.write_qname(rname)?;
+                buffer.write_u32(serial)?;
+                buffer.write_u32(refresh)?;
+                buffer.write_u32(retry)?;
+                buffer.write_u32(expire)?;
+                buffer.write_u32(minimum)?;
+
+                let size = buffer.pos() - (pos + 2);
+                buffer.set_u16(pos, size as u16)?;
+            }
+            DnsRecord::MX {
+                ref domain,
+                class,
+                priority,
+                ref host,
+                ttl,
+            } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::MX.to_num())?;
+                buffer.write_u16(class.to_num())?;
+                buffer.write_u32(ttl)?;
+
+                let pos = buffer.pos();
+                buffer.write_u16(0)?;
+
+                buffer.write_u16(priority)?;
+                buffer.write_qname(host)?;
+
+                let size = buffer.pos() - (pos + 2);
+                buffer.set_u16(pos, size as u16)?;
+            }
+            DnsRecord::TXT {
+                ref domain,
+                class,
+                ref strings,
+                ttl,
+            } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::TXT.to_num())?;
+                buffer.write_u16(class.to_num())?;
+                buffer.write_u32(ttl)?;
+
+                let pos = buffer.pos();
+                buffer.write_u16(0)?;
+
+                for string in strings {
+                    if string.len() > 0xFF {
+                        return Err(anyhow!("TXT character-string exceeds 255 bytes"));
+                    }
+                    buffer.write_u8(string.len() as u8)?;
+                    buffer.write_bytes(string)?;
+                }
+
+                let size = buffer.pos() - (pos + 2);
+                buffer.set_u16(pos, size as u16)?;
+            }
+            DnsRecord::SRV {
+                ref domain,
+                class,
+                priority,
+                weight,
+                port,
+                ref target,
+                ttl,
+            } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::SRV.to_num())?;
+                buffer.write_u16(class.to_num())?;
+                buffer.write_u32(ttl)?;
+
+                let pos = buffer.pos();
+                buffer.write_u16(0)?;
+
+                buffer.write_u16(priority)?;
+                buffer.write_u16(weight)?;
+                buffer.write_u16(port)?;
+                // RFC 2782 forbids compressing the SRV target
+                buffer.write_qname_uncompressed(target)?;
+
+                let size = buffer.pos() - (pos + 2);
+                buffer.set_u16(pos, size as u16)?;
+            }
+            DnsRecord::UNKNOWN {
+                ref domain,
+                class,
+                qtype,
+                ref data,
+                ttl,
+            } => {
+                if data.len() > 0xFFFF {
+                    return Err(anyhow!("RDATA exceeds 65535 bytes"));
+                }
+
+                buffer.write_qname(domain)?;
+                buffer.write_u16(qtype)?;
+                buffer.write_u16(class.to_num())?;
+                buffer.write_u32(ttl)?;
+                buffer.write_u16(data.len() as u16)?;
+                buffer.write_bytes(data)?;
             }
         }
 
         Ok(buffer.pos() - start_pos)
     }
-}
\ No newline at end of file
+
+    /// Write a record whose RDATA is a single domain name (NS, CNAME, PTR).
+    /// The RDLENGTH is backfilled once the name has been written.
+    fn write_name_record(
+        buffer: &mut BytePacketBuffer,
+        domain: &str,
+        class: QueryClass,
+        qtype: QueryType,
+        ttl: u32,
+        host: &str,
+    ) -> Result<()> {
+        buffer.write_qname(domain)?;
+        buffer.write_u16(qtype.to_num())?;
+        buffer.write_u16(class.to_num())?;
+        buffer.write_u32(ttl)?;
+
+        let pos = buffer.pos();
+        buffer.write_u16(0)?;
+
+        buffer.write_qname(host)?;
+
+        let size = buffer.pos() - (pos + 2);
+        buffer.set_u16(pos, size as u16)?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn empty_rdata_does_not_swallow_the_next_record() {
+        let mut bytes = Vec::new();
+        // example.com NONE NS, TTL 0, RDLENGTH 0: an update prerequisite
+        bytes.extend_from_slice(b"\x07example\x03com\x00\x00\x02\x00\xfe\x00\x00\x00\x00\x00\x00");
+        // example.com IN A 192.0.2.1
+        bytes.extend_from_slice(b"\xc0\x00\x00\x01\x00\x01\x00\x00\x00\x3c\x00\x04\xc0\x00\x02\x01");
+
+        let mut buffer = BytePacketBuffer::from_bytes(&bytes);
+        let prereq = DnsRecord::read(&mut buffer).unwrap();
+        assert_eq!(
+            prereq,
+            DnsRecord::UNKNOWN {
+                domain: "example.com".to_string(),
+                class: QueryClass::NONE,
+                qtype: 2,
+                data: Vec::new(),
+                ttl: 0,
+            }
+        );
+        // Still an NS record as far as RRsets and the cache are concerned
+        assert_eq!(prereq.qtype(), QueryType::NS);
+
+        let a = DnsRecord::read(&mut buffer).unwrap();
+        assert_eq!(a, DnsRecord::new_a("example.com".to_string(), Ipv4Addr::new(192, 0, 2, 1), 60));
+
+        let mut out = BytePacketBuffer::with_max_size(512);
+        prereq.write(&mut out).unwrap();
+        assert_eq!(out.as_slice(), &bytes[..23]);
+    }
+
+    #[test]
+    fn rdata_shorter_than_its_type_is_rejected() {
+        // An A record claiming only two bytes of RDATA
+        let bytes = b"\x00\x00\x01\x00\x01\x00\x00\x00\x3c\x00\x02\xc0\x00\x02\x01";
+        let mut buffer = BytePacketBuffer::from_bytes(bytes);
+        assert!(DnsRecord::read(&mut buffer).is_err());
+    }
+}
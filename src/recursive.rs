@@ -0,0 +1,416 @@
+use std::future::Future;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::pin::Pin;
+
+use anyhow::{Result, anyhow};
+
+use crate::{
+    header::ResultCode,
+    name::{is_below, is_subdomain, label_count},
+    packet::{DnsPacket, OPT_TYPE},
+    query::{DnsQuestion, QueryType},
+    record::DnsRecord,
+    upstream::{self, UpstreamOptions},
+};
+
+/// The IPv4 addresses of a.root-servers.net through m.root-servers.net
+pub const ROOT_HINTS: [Ipv4Addr; 13] = [
+    Ipv4Addr::new(198, 41, 0, 4),
+    Ipv4Addr::new(170, 247, 170, 2),
+    Ipv4Addr::new(192, 33, 4, 12),
+    Ipv4Addr::new(199, 7, 91, 13),
+    Ipv4Addr::new(192, 203, 230, 10),
+    Ipv4Addr::new(192, 5, 5, 241),
+    Ipv4Addr::new(192, 112, 36, 4),
+    Ipv4Addr::new(198, 97, 190, 53),
+    Ipv4Addr::new(192, 36, 148, 17),
+    Ipv4Addr::new(192, 58, 128, 30),
+    Ipv4Addr::new(193, 0, 14, 129),
+    Ipv4Addr::new(199, 7, 83, 42),
+    Ipv4Addr::new(202, 12, 27, 33),
+];
+
+/// Referrals followed for one name before giving up
+const MAX_REFERRALS: usize = 20;
+
+/// Nested resolutions (CNAME targets, nameserver addresses) before giving up
+const MAX_DEPTH: usize = 8;
+
+/// Most minimised queries sent for one name (RFC 9156 section 2.3)
+const MAX_MINIMISE_COUNT: usize = 10;
+
+/// Minimised queries that reveal a single label each before later ones
+/// start revealing several at a time
+const MINIMISE_ONE_LAB: usize = 4;
+
+type BoxedLookup<'a> = Pin<Box<dyn Future<Output = Result<DnsPacket>> + Send + 'a>>;
+
+/// Resolves names itself by walking down from the root, instead of asking
+/// an upstream resolver to do it
+#[derive(Debug)]
+pub struct RecursiveResolver {
+    root_hints: Vec<SocketAddr>,
+    /// Port used for every nameserver learned from a referral
+    ns_port: u16,
+    /// Only reveal one more label of the name to each authority
+    qname_minimisation: bool,
+    options: UpstreamOptions,
+}
+
+impl RecursiveResolver {
+    pub fn new(
+        root_hints: Vec<SocketAddr>,
+        ns_port: u16,
+        qname_minimisation: bool,
+        options: &UpstreamOptions,
+    ) -> RecursiveResolver {
+        RecursiveResolver {
+            root_hints,
+            ns_port,
+            qname_minimisation,
+            // Authorities don't recurse for us, and shouldn't be asked to
+            options: UpstreamOptions {
+                recursion_desired: false,
+                ..options.clone()
+            },
+        }
+    }
+
+    pub async fn resolve(&self, question: &DnsQuestion) -> Result<DnsPacket> {
+        self.resolve_at_depth(question.clone(), 0).await
+    }
+
+    fn resolve_at_depth(&self, question: DnsQuestion, depth: usize) -> BoxedLookup<'_> {
+        Box::pin(async move {
+            if depth > MAX_DEPTH {
+                return Err(anyhow!("Too much nesting resolving {}", question.name));
+            }
+
+            let (mut response, zone) = self.iterate(&question, depth).await?;
+
+            // The name is an alias: follow the chain as far as the response
+            // itself goes, and only go looking for the rest if it stops short
+            // of any data for the type asked. The server is only trusted for
+            // targets in its own zone; the rest are looked up from the root.
+            if question.qtype != QueryType::CNAME {
+                let mut name = question.name.clone();
+                let mut left_zone = false;
+                for _ in 0..response.answers.len() {
+                    if has_answer(&response, &name, question.qtype) {
+                        break;
+                    }
+                    match cname_target(&response, &name) {
+                        Some(target) => {
+                            left_zone = !is_subdomain(&target, &zone);
+                            name = target;
+                            if left_zone {
+                                break;
+                            }
+                        }
+                        None => break,
+                    }
+                }
+
+                // Nor is its rcode, once the chain has left the zone
+                let chain_ends_short = !name.eq_ignore_ascii_case(&question.name)
+                    && !has_answer(&response, &name, question.qtype);
+                if chain_ends_short && (left_zone || response.header.rescode == ResultCode::NOERROR) {
+                    println!("Following CNAME {} -> {}", question.name, name);
+                    let target_question = DnsQuestion {
+                        name,
+                        qtype: question.qtype,
+                        class: question.class,
+                    };
+                    let target_response = self.resolve_at_depth(target_question, depth + 1).await?;
+
+                    response.header.rescode = target_response.header.rescode;
+                    response.answers.extend(target_response.answers);
+                    response.authorities = target_response.authorities;
+                    response.resources = target_response.resources;
+                }
+            }
+
+            response.questions = vec![question];
+            Ok(response)
+        })
+    }
+
+    /// Follow referrals from the root until some server gives a final answer
+    /// (records, NXDOMAIN, or NODATA) for exactly this name. With QNAME
+    /// minimisation each server is only asked about the name one label below
+    /// the zone it serves (RFC 9156), falling back to the full name if it
+    /// chokes on the shortened one. Returns the response along with the zone
+    /// of the server that gave it.
+    async fn iterate(&self, question: &DnsQuestion, depth: usize) -> Result<(DnsPacket, String)> {
+        let mut servers = self.root_hints.clone();
+        let mut zone = String::new();
+        // The longest name known not to be a zone cut, or the zone itself
+        let mut asked = String::new();
+        let mut minimise = self.qname_minimisation;
+        let mut minimised_count = 0;
+        let mut referrals = 0;
+
+        loop {
+            let hidden = label_count(&question.name).saturating_sub(label_count(&asked));
+            let reveal = labels_to_reveal(minimised_count, hidden);
+            let response = if minimise && reveal < hidden {
+                minimised_count += 1;
+
+                // A is what a real query for the name most likely looks like,
+                // so it gives away the least (RFC 9156 section 2.1)
+                let qname = child_name(&question.name, &asked, reveal);
+                let minimised_question = DnsQuestion {
+                    name: qname.clone(),
+                    qtype: QueryType::A,
+                    class: question.class,
+                };
+
+                match self.query_servers(&minimised_question, &servers, &zone).await {
+                    Ok(response) if response.header.rescode == ResultCode::NOERROR => {
+                        match referral(&response, &qname) {
+                            Some(referral) if is_below(&referral.0, &zone) => response,
+                            // No cut here, so the next server is the same one
+                            _ => {
+                                asked = qname;
+                                continue;
+                            }
+                        }
+                    }
+                    // Some authorities wrongly deny names that only have
+                    // children, or fail on them outright
+                    Ok(_) => {
+                        println!("{} denied by {}, asking for {} instead", qname, zone, question.name);
+                        minimise = false;
+                        continue;
+                    }
+                    Err(e) => {
+                        println!("Asking for {} failed ({}), asking for {} instead", qname, e, question.name);
+                        minimise = false;
+                        continue;
+                    }
+                }
+            } else {
+                let response = self.query_servers(question, &servers, &zone).await?;
+                if !response.answers.is_empty() || response.header.rescode == ResultCode::NXDOMAIN {
+                    return Ok((response, zone));
+                }
+                response
+            };
+
+            // A referral must move us strictly closer to the name, otherwise
+            // we would go round in circles
+            let Some((child, hosts)) = referral(&response, &question.name) else {
+                return Ok((response, zone));
+            };
+            if !is_below(&child, &zone) {
+                return Err(anyhow!("Referral from {} to {} does not go down", zone, child));
+            }
+
+            referrals += 1;
+            if referrals > MAX_REFERRALS {
+                return Err(anyhow!("Too many referrals resolving {}", question.name));
+            }
+
+            println!("Referred to {} for {}", child, question.name);
+            zone = child.clone();
+            asked = child;
+
+            servers = glue(&response, &hosts, self.ns_port);
+            if servers.is_empty() {
+                servers = self.resolve_nameservers(&hosts, depth).await?;
+            }
+        }
+    }
+
+    /// Find an address for the first of `hosts` we can resolve, for
+    /// nameservers whose names are outside the zone and came without glue
+    async fn resolve_nameservers(&self, hosts: &[String], depth: usize) -> Result<Vec<SocketAddr>> {
+        for host in hosts {
+            let question = DnsQuestion::new(host.clone(), QueryType::A);
+            match self.resolve_at_depth(question, depth + 1).await {
+                Ok(response) => {
+                    let addrs = addresses(&response.answers, host, self.ns_port);
+                    if !addrs.is_empty() {
+                        return Ok(addrs);
+                    }
+                }
+                Err(e) => println!("Could not resolve nameserver {}: {}", host, e),
+            }
+        }
+
+        Err(anyhow!("No address for any of the nameservers {:?}", hosts))
+    }
+
+    /// Ask each server for `zone` in turn until one gives a usable response,
+    /// keeping only the records it has authority over
+    async fn query_servers(&self, question: &DnsQuestion, servers: &[SocketAddr], zone: &str) -> Result<DnsPacket> {
+        for &server in servers {
+            match upstream::lookup(question, server, &self.options).await {
+                Ok(mut response) if matches!(response.header.rescode, ResultCode::NOERROR | ResultCode::NXDOMAIN) => {
+                    drop_out_of_zone(&mut response, zone);
+                    return Ok(response);
+                }
+                Ok(response) => println!("Server {} answered {:?}", server, response.header.rescode),
+                Err(e) => println!("Server {} failed: {}", server, e),
+            }
+        }
+
+        Err(anyhow!("No server could answer {}", question.name))
+    }
+}
+
+/// Drop the records a server for `zone` has no say over, so that whatever
+/// it claims about other zones is never relayed or cached
+fn drop_out_of_zone(response: &mut DnsPacket, zone: &str) {
+    let in_zone = |rec: &DnsRecord| is_subdomain(rec.domain(), zone);
+    let before = response.answers.len() + response.authorities.len() + response.resources.len();
+
+    response.answers.retain(in_zone);
+    response.authorities.retain(in_zone);
+    response
+        .resources
+        .retain(|rec| in_zone(rec) || rec.qtype() == QueryType::UNKNOWN(OPT_TYPE));
+
+    let dropped = before - response.answers.len() - response.authorities.len() - response.resources.len();
+    if dropped > 0 {
+        println!("Dropped {} records from outside {}", dropped, zone);
+    }
+}
+
+fn has_answer(response: &DnsPacket, name: &str, qtype: QueryType) -> bool {
+    response
+        .answers
+        .iter()
+        .any(|rec| rec.qtype() == qtype && rec.domain().eq_ignore_ascii_case(name))
+}
+
+fn cname_target(response: &DnsPacket, name: &str) -> Option<String> {
+    response.answers.iter().find_map(|rec| match rec {
+        DnsRecord::CNAME { domain, host, .. } if domain.eq_ignore_ascii_case(name) => Some(host.clone()),
+        _ => None,
+    })
+}
+
+/// The delegated zone and its nameserver names, if the authority section
+/// holds NS records for an ancestor of `name`
+fn referral(response: &DnsPacket, name: &str) -> Option<(String, Vec<String>)> {
+    let mut zone = None;
+    let mut hosts = Vec::new();
+
+    for rec in &response.authorities {
+        if let DnsRecord::NS { domain, host, .. } = rec {
+            if !is_subdomain(name, domain) {
+                continue;
+            }
+            match &zone {
+                None => zone = Some(domain.to_lowercase()),
+                Some(zone) if !zone.eq_ignore_ascii_case(domain) => continue,
+                Some(_) => {}
+            }
+            hosts.push(host.clone());
+        }
+    }
+
+    zone.map(|zone| (zone, hosts))
+}
+
+/// Addresses for `hosts` from the additional section
+fn glue(response: &DnsPacket, hosts: &[String], port: u16) -> Vec<SocketAddr> {
+    hosts
+        .iter()
+        .flat_map(|host| addresses(&response.resources, host, port))
+        .collect()
+}
+
+/// A and AAAA records owned by `host`, as socket addresses
+fn addresses(records: &[DnsRecord], host: &str, port: u16) -> Vec<SocketAddr> {
+    records
+        .iter()
+        .filter(|rec| rec.domain().eq_ignore_ascii_case(host))
+        .filter_map(|rec| match rec {
+            DnsRecord::A { addr, .. } => Some(SocketAddr::new(IpAddr::V4(*addr), port)),
+            DnsRecord::AAAA { addr, .. } => Some(SocketAddr::new(IpAddr::V6(*addr), port)),
+            _ => None,
+        })
+        .collect()
+}
+
+/// How many more labels of the name the next minimised query reveals, with
+/// `count` sent so far and `hidden` labels not yet revealed. The first few
+/// add one label each, then the rest are spread out so that there are never
+/// more than `MAX_MINIMISE_COUNT` in all (RFC 9156 section 2.3).
+fn labels_to_reveal(count: usize, hidden: usize) -> usize {
+    if count < MINIMISE_ONE_LAB {
+        1
+    } else if count < MAX_MINIMISE_COUNT {
+        (hidden / (MAX_MINIMISE_COUNT - count)).max(1)
+    } else {
+        hidden
+    }
+}
+
+/// `ancestor` with the next `count` labels of `name` below it prepended,
+/// e.g. `example.com` for `www.example.com` below `com` with a count of 1
+fn child_name(name: &str, ancestor: &str, count: usize) -> String {
+    let labels: Vec<&str> = name.split('.').filter(|l| !l.is_empty()).collect();
+    let keep = (label_count(ancestor) + count).min(labels.len());
+    labels[labels.len() - keep..].join(".")
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::query::QueryClass;
+
+    use super::*;
+
+    #[test]
+    fn long_names_take_at_most_max_minimise_count_queries() {
+        // A reverse IPv6 name: 32 nibbles plus ip6.arpa
+        let name = format!("{}ip6.arpa", "0.".repeat(32));
+        let mut asked = String::new();
+        let mut queries = Vec::new();
+
+        loop {
+            let hidden = label_count(&name) - label_count(&asked);
+            let reveal = labels_to_reveal(queries.len(), hidden);
+            if reveal >= hidden {
+                break;
+            }
+            asked = child_name(&name, &asked, reveal);
+            queries.push(asked.clone());
+        }
+
+        assert!(queries.len() <= MAX_MINIMISE_COUNT);
+        assert_eq!(queries[0], "arpa");
+        assert_eq!(queries[MINIMISE_ONE_LAB - 1], "0.0.ip6.arpa");
+    }
+
+    #[test]
+    fn records_from_outside_the_zone_are_dropped() {
+        let mut response = DnsPacket::new();
+        response.answers.push(DnsRecord::CNAME {
+            domain: "alias.example.com".to_string(),
+            class: QueryClass::IN,
+            host: "www.other.net".to_string(),
+            ttl: 300,
+        });
+        response.answers.push(DnsRecord::new_a("www.other.net".to_string(), Ipv4Addr::new(6, 6, 6, 6), 86400));
+        response.resources.push(DnsRecord::new_a("ns.other.net".to_string(), Ipv4Addr::new(6, 6, 6, 7), 86400));
+        response.add_edns();
+
+        drop_out_of_zone(&mut response, "example.com");
+
+        assert_eq!(response.answers.len(), 1);
+        assert_eq!(response.answers[0].qtype(), QueryType::CNAME);
+        assert!(response.resources.iter().all(|rec| rec.qtype() == QueryType::UNKNOWN(OPT_TYPE)));
+        assert_eq!(cname_target(&response, "alias.example.com").as_deref(), Some("www.other.net"));
+        assert!(!has_answer(&response, "www.other.net", QueryType::A));
+    }
+
+    #[test]
+    fn short_names_reveal_one_label_at_a_time() {
+        assert_eq!(child_name("www.example.com", "", 1), "com");
+        assert_eq!(child_name("www.example.com", "com", 1), "example.com");
+        assert_eq!(labels_to_reveal(1, 2), 1);
+    }
+}
@@ -0,0 +1,371 @@
+use std::io::ErrorKind;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{Result, anyhow};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::sync::{Mutex, Semaphore};
+use tokio::time::timeout;
+
+use crate::{
+    byte_packet_buffer::{BytePacketBuffer, TCP_MAX_SIZE},
+    cache::Cache,
+    config::Config,
+    forward::Forwarder,
+    header::ResultCode,
+    packet::{DnsPacket, EDNS_MAX_SIZE},
+    query::{DnsQuestion, QueryType},
+    recursive::RecursiveResolver,
+    zone::Zones,
+};
+
+/// How long a TCP connection may sit idle, or take to send a message,
+/// before we close it (RFC 7766 section 6.2.3)
+const TCP_IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Pipelined queries resolved at once on one TCP connection; further
+/// queries are not read until one of these finishes
+const MAX_TCP_IN_FLIGHT: usize = 16;
+
+/// Everything a query handler needs, shared across all tasks
+pub struct ServerContext {
+    pub config: Config,
+    pub forwarder: Forwarder,
+    pub cache: Cache,
+    /// Set in recursive mode, for names no forwarding rule covers
+    pub recursor: Option<RecursiveResolver>,
+    pub zones: Zones,
+}
+
+impl ServerContext {
+    pub fn new(config: Config) -> ServerContext {
+        let forwarder = Forwarder::new(&config.resolvers, &config.forwards, config.strategy);
+        let cache = Cache::new(config.cache_size, config.stale_window, config.prefetch);
+        let recursor = config.recursive.then(|| {
+            RecursiveResolver::new(
+                config.root_hints.clone(),
+                config.ns_port,
+                config.qname_minimisation,
+                &config.upstream,
+            )
+        });
+        let zones = Zones::new(config.zones.clone());
+        ServerContext {
+            config,
+            forwarder,
+            cache,
+            recursor,
+            zones,
+        }
+    }
+
+    /// Whether names outside our own zones get resolved at all, either by
+    /// forwarding or by recursion
+    pub fn recursion_available(&self) -> bool {
+        !self.config.resolvers.is_empty() || !self.config.forwards.is_empty() || self.recursor.is_some()
+    }
+}
+
+/// Build the response to a client query. Shared by the UDP and TCP
+/// listeners so both transports answer identically.
+pub async fn handle_query(packet: &DnsPacket, context: &Arc<ServerContext>) -> Result<DnsPacket> {
+    // Prepare the response packet
+    let mut response_packet = DnsPacket::new();
+    response_packet.header.id = packet.header.id;
+    response_packet.header.response = true;
+    response_packet.header.opcode = packet.header.opcode;
+    response_packet.header.authoritative_answer = false;
+    response_packet.header.truncated_message = false;
+    response_packet.header.recursion_desired = packet.header.recursion_desired;
+    response_packet.header.recursion_available = context.recursion_available();
+    response_packet.header.z = false;
+    response_packet.header.checking_disabled = packet.header.checking_disabled;
+    response_packet.header.authed_data = packet.header.authed_data;
+
+    // Check the opcode in the incoming query
+    if packet.header.opcode != 0 {
+        // Return NOTIMP (Not Implemented) for unsupported opcodes
+        response_packet.header.rescode = ResultCode::NOTIMP;
+        response_packet.questions = packet.questions.clone();
+        if packet.questions.is_empty(){
+            response_packet.questions.push(DnsQuestion::new("codecrafters.io".to_string(), QueryType::A));
+        }
+        response_packet.header.questions = 1;
+    } else if !packet.questions.is_empty() {
+        // Process the questions only for standard queries (Opcode 0)
+        let mut rescode = ResultCode::NOERROR;
+        let mut authoritative = true;
+        for question in &packet.questions {
+            // Resolve each question individually. If no resolver can be
+            // reached the client gets SERVFAIL rather than silence.
+            let resolver_response_packet =
+                match resolve_question(question, context).await {
+                    Ok(resolver_response_packet) => resolver_response_packet,
+                    Err(e) => {
+                        println!("Failed to resolve {}: {}", question.name, e);
+                        return Ok(servfail(packet, context));
+                    }
+                };
+
+            // Relay NXDOMAIN and friends rather than masking them as NOERROR
+            if resolver_response_packet.header.rescode != ResultCode::NOERROR {
+                rescode = resolver_response_packet.header.rescode;
+            }
+            authoritative &= resolver_response_packet.header.authoritative_answer;
+
+            response_packet.questions.extend(resolver_response_packet.questions);
+            // Copy answers, authorities, and additional records from resolver's response
+            response_packet.answers.extend(resolver_response_packet.answers);
+            response_packet.authorities.extend(resolver_response_packet.authorities);
+            response_packet.resources.extend(resolver_response_packet.resources);
+        }
+
+        // Update header with the number of responses
+
+        response_packet.header.answers = response_packet.answers.len() as u16;
+        response_packet.header.authoritative_entries = response_packet.authorities.len() as u16;
+        response_packet.header.resource_entries = response_packet.resources.len() as u16;
+
+        // NOERROR unless a resolver said otherwise
+        response_packet.header.rescode = rescode;
+        response_packet.header.authoritative_answer = authoritative;
+        response_packet.header.questions = packet.questions.len() as u16;
+    }
+
+    // Answer EDNS with EDNS
+    if packet.edns().is_some() {
+        response_packet.add_edns();
+    }
+
+    Ok(response_packet)
+}
+
+/// Answer a single question from our own zones or the cache, or forward it
+/// to the matching resolvers and cache what comes back. When an expired
+/// answer is still within the stale window it is served instead if upstream
+/// fails or is slower than the stale answer timeout; the lookup then carries
+/// on in the background to refresh the cache (RFC 8767). Only one refresh
+/// per entry runs at a time, and none for a while after one has failed.
+async fn resolve_question(question: &DnsQuestion, context: &Arc<ServerContext>) -> Result<DnsPacket> {
+    if let Some(response) = context.zones.answer(question) {
+        println!("Answering {} {:?} authoritatively", question.name, question.qtype);
+        return Ok(response);
+    }
+
+    if let Some(cached) = context.cache.get(question) {
+        println!("Answering {} {:?} from cache", question.name, question.qtype);
+
+        // Refresh popular entries before they expire so their clients never
+        // see a miss
+        if context.cache.claim_prefetch(question) {
+            println!("Prefetching {} {:?}", question.name, question.qtype);
+            let question = question.clone();
+            let context = context.clone();
+            tokio::spawn(async move {
+                if let Err(e) = refresh_question(question, context).await {
+                    println!("Prefetch failed: {}", e);
+                }
+            });
+        }
+
+        return Ok(cached);
+    }
+
+    let Some(stale) = context.cache.get_stale(question) else {
+        return forward_question(question.clone(), context.clone()).await;
+    };
+
+    if !context.cache.claim_refresh(question) {
+        println!("Serving stale answer for {} without asking upstream again yet", question.name);
+        return Ok(stale);
+    }
+
+    let mut refresh = tokio::spawn(refresh_question(question.clone(), context.clone()));
+    match timeout(context.config.stale_answer_timeout, &mut refresh).await {
+        Ok(Ok(Ok(response))) => Ok(response),
+        Ok(Ok(Err(e))) => {
+            println!("Serving stale answer for {} after upstream failure: {}", question.name, e);
+            Ok(stale)
+        }
+        Ok(Err(e)) => {
+            println!("Serving stale answer for {} after refresh panicked: {}", question.name, e);
+            Ok(stale)
+        }
+        // Dropping the handle leaves the refresh running in the background
+        Err(_) => {
+            println!("Serving stale answer for {} while upstream catches up", question.name);
+            Ok(stale)
+        }
+    }
+}
+
+/// Forward a question to the matching resolvers, or resolve it from the
+/// root in recursive mode, and cache the response. A server that only has
+/// zones of its own refuses everything else.
+async fn forward_question(question: DnsQuestion, context: Arc<ServerContext>) -> Result<DnsPacket> {
+    let mut response = match (context.forwarder.pool_for(&question.name), &context.recursor) {
+        (Some(pool), _) => pool.lookup(&question, &context.config.upstream).await?,
+        (None, Some(recursor)) => recursor.resolve(&question).await?,
+        (None, None) => {
+            let mut refused = DnsPacket::new();
+            refused.header.rescode = ResultCode::REFUSED;
+            refused.questions.push(question);
+            return Ok(refused);
+        }
+    };
+    // Whoever answered may be authoritative, but we are only passing it on
+    response.header.authoritative_answer = false;
+    context.cache.insert(&question, &response);
+
+    Ok(response)
+}
+
+/// Forward a question on behalf of a cache entry that claimed a refresh,
+/// letting the cache know how it went
+async fn refresh_question(question: DnsQuestion, context: Arc<ServerContext>) -> Result<DnsPacket> {
+    let result = forward_question(question.clone(), context.clone()).await;
+    context.cache.finish_refresh(&question, result.is_err());
+    result
+}
+
+/// An empty SERVFAIL reply echoing the client's questions
+fn servfail(packet: &DnsPacket, context: &ServerContext) -> DnsPacket {
+    let mut response_packet = DnsPacket::new();
+    response_packet.header.id = packet.header.id;
+    response_packet.header.response = true;
+    response_packet.header.opcode = packet.header.opcode;
+    response_packet.header.recursion_desired = packet.header.recursion_desired;
+    response_packet.header.recursion_available = context.recursion_available();
+    response_packet.header.checking_disabled = packet.header.checking_disabled;
+    response_packet.header.rescode = ResultCode::SERVFAIL;
+    response_packet.questions = packet.questions.clone();
+
+    if packet.edns().is_some() {
+        response_packet.add_edns();
+    }
+
+    response_packet
+}
+
+/// Answer queries arriving on the UDP socket. Every datagram is handled on
+/// its own task, so a slow upstream only holds up the query waiting on it.
+pub async fn serve_udp(udp_socket: UdpSocket, context: Arc<ServerContext>) -> Result<()> {
+    let udp_socket = Arc::new(udp_socket);
+    // Reused for every datagram; each task gets a copy of just its query
+    let mut buf = vec![0u8; TCP_MAX_SIZE];
+
+    loop {
+        // Receive DNS query from the client
+        let (amt, src) = udp_socket.recv_from(&mut buf).await?;
+
+        if amt > 0 {
+            let data = buf[..amt].to_vec();
+
+            let udp_socket = udp_socket.clone();
+            let context = context.clone();
+            tokio::spawn(async move {
+                if let Err(e) = handle_udp_query(&udp_socket, &data, src, &context).await {
+                    println!("Failed to answer query from {}: {}", src, e);
+                }
+            });
+        }
+    }
+}
+
+async fn handle_udp_query(udp_socket: &UdpSocket, data: &[u8], src: SocketAddr, context: &Arc<ServerContext>) -> Result<()> {
+    // Parse the incoming packet
+    let mut buffer = BytePacketBuffer::from_bytes(data);
+    let packet = DnsPacket::from_buffer(&mut buffer)?;
+
+    let mut response_packet = handle_query(&packet, context).await?;
+
+    // Fit the response into whatever UDP size the client allows;
+    // anything larger is truncated with TC set
+    let mut response_buffer = BytePacketBuffer::with_max_size(packet.max_udp_size().min(EDNS_MAX_SIZE));
+    response_packet.write(&mut response_buffer)?;
+
+    println!("Sending response back to client at {}", src);
+    udp_socket.send_to(response_buffer.as_slice(), src).await?;
+
+    Ok(())
+}
+
+/// Accept TCP connections, handling each one on its own task
+pub async fn serve_tcp(listener: TcpListener, context: Arc<ServerContext>) -> Result<()> {
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(conn) => conn,
+            Err(e) => {
+                println!("Failed to accept TCP connection: {}", e);
+                continue;
+            }
+        };
+
+        let context = context.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_tcp_connection(stream, context).await {
+                println!("TCP connection from {} failed: {}", peer, e);
+            }
+        });
+    }
+}
+
+/// Answer every length-prefixed query sent on a connection (RFC 1035
+/// section 4.2.2). Pipelined queries are resolved concurrently and each
+/// response is written as soon as it is ready, which may be out of order
+/// (RFC 7766 section 6.2.1.1). Idle connections are closed, and at most
+/// `MAX_TCP_IN_FLIGHT` queries per connection are worked on at a time.
+async fn handle_tcp_connection(stream: TcpStream, context: Arc<ServerContext>) -> Result<()> {
+    let (mut reader, writer) = stream.into_split();
+    let writer = Arc::new(Mutex::new(writer));
+    let in_flight = Arc::new(Semaphore::new(MAX_TCP_IN_FLIGHT));
+
+    loop {
+        let permit = in_flight.clone().acquire_owned().await?;
+
+        let mut len_bytes = [0u8; 2];
+        match timeout(TCP_IDLE_TIMEOUT, reader.read_exact(&mut len_bytes)).await {
+            Ok(Ok(_)) => {}
+            // The client closed the connection between messages
+            Ok(Err(e)) if e.kind() == ErrorKind::UnexpectedEof => return Ok(()),
+            Ok(Err(e)) => return Err(e.into()),
+            Err(_) => return Ok(()),
+        }
+
+        let len = u16::from_be_bytes(len_bytes) as usize;
+        let mut data = vec![0u8; len];
+        timeout(TCP_IDLE_TIMEOUT, reader.read_exact(&mut data))
+            .await
+            .map_err(|_| anyhow!("Timed out reading a {} byte message", len))??;
+
+        let mut buffer = BytePacketBuffer::from_bytes(&data);
+        let packet = DnsPacket::from_buffer(&mut buffer)?;
+
+        let writer = writer.clone();
+        let context = context.clone();
+        tokio::spawn(async move {
+            let _permit = permit;
+            let result: Result<()> = async {
+                let mut response_packet = handle_query(&packet, &context).await?;
+
+                let mut response_buffer = BytePacketBuffer::with_max_size(TCP_MAX_SIZE);
+                response_packet.write(&mut response_buffer)?;
+
+                // Hold the lock across both writes so responses don't interleave
+                let response = response_buffer.as_slice();
+                let mut writer = writer.lock().await;
+                writer.write_all(&(response.len() as u16).to_be_bytes()).await?;
+                writer.write_all(response).await?;
+
+                Ok(())
+            }
+            .await;
+
+            if let Err(e) = result {
+                println!("Failed to answer TCP query: {}", e);
+            }
+        });
+    }
+}
@@ -0,0 +1,32 @@
+use std::net::Ipv4Addr;
+
+use crate::{query::QueryClass, record::DnsRecord};
+
+/// The SOA of the `example.com` zone most tests use
+pub fn soa(ttl: u32, minimum: u32) -> DnsRecord {
+    DnsRecord::SOA {
+        domain: "example.com".to_string(),
+        class: QueryClass::IN,
+        mname: "ns1.example.com".to_string(),
+        rname: "hostmaster.example.com".to_string(),
+        serial: 1,
+        refresh: 3600,
+        retry: 600,
+        expire: 86400,
+        minimum,
+        ttl,
+    }
+}
+
+pub fn a(domain: &str, addr: [u8; 4], ttl: u32) -> DnsRecord {
+    DnsRecord::new_a(domain.to_string(), Ipv4Addr::from(addr), ttl)
+}
+
+pub fn ns(domain: &str, host: &str) -> DnsRecord {
+    DnsRecord::NS {
+        domain: domain.to_string(),
+        class: QueryClass::IN,
+        host: host.to_string(),
+        ttl: 300,
+    }
+}
@@ -0,0 +1,188 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+
+use anyhow::{Result, anyhow};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpStream, UdpSocket};
+use tokio::time::{sleep, timeout};
+use rand::Rng;
+
+use crate::{
+    byte_packet_buffer::{BytePacketBuffer, TCP_MAX_SIZE},
+    packet::DnsPacket,
+    query::DnsQuestion,
+};
+
+/// Longest pause between retries, however many there are
+const MAX_BACKOFF: Duration = Duration::from_secs(5);
+
+/// How patiently we wait on an upstream resolver
+#[derive(Clone, Debug)]
+pub struct UpstreamOptions {
+    /// Time allowed for a single attempt, including any TCP fallback
+    pub timeout: Duration,
+    /// Further attempts after the first one fails
+    pub retries: u32,
+    /// Pause before the first retry, doubled for each one after that
+    pub backoff: Duration,
+    /// Sets RD on outgoing queries: on when forwarding to a recursive
+    /// resolver, off when asking authoritative servers directly
+    pub recursion_desired: bool,
+}
+
+impl Default for UpstreamOptions {
+    fn default() -> Self {
+        UpstreamOptions {
+            timeout: Duration::from_millis(2000),
+            retries: 2,
+            backoff: Duration::from_millis(100),
+            recursion_desired: true,
+        }
+    }
+}
+
+impl UpstreamOptions {
+    /// The pause after `delay` when another retry fails: doubled, but no
+    /// more than `MAX_BACKOFF` unless the first pause was already longer
+    pub fn next_backoff(&self, delay: Duration) -> Duration {
+        delay.saturating_mul(2).min(MAX_BACKOFF.max(self.backoff))
+    }
+}
+
+/// Ask the resolver a single question, retrying lost or failed attempts
+/// with exponential backoff. Errors once every attempt has failed.
+pub async fn lookup(
+    question: &DnsQuestion,
+    resolver_addr: SocketAddr,
+    options: &UpstreamOptions,
+) -> Result<DnsPacket> {
+    let mut delay = options.backoff;
+
+    for attempt in 0..=options.retries {
+        if attempt > 0 {
+            sleep(delay).await;
+            delay = options.next_backoff(delay);
+        }
+
+        match timeout(options.timeout, lookup_once(question, resolver_addr, options.recursion_desired)).await {
+            Ok(Ok(response)) => return Ok(response),
+            Ok(Err(e)) => println!("Attempt {} to {} failed: {}", attempt + 1, resolver_addr, e),
+            Err(_) => println!("Attempt {} to {} timed out", attempt + 1, resolver_addr),
+        }
+    }
+
+    Err(anyhow!(
+        "No answer from {} after {} attempts",
+        resolver_addr,
+        options.retries + 1
+    ))
+}
+
+/// Ask the resolver a single question over UDP. If the answer comes back
+/// truncated, the same query is repeated over TCP to get the full response.
+async fn lookup_once(question: &DnsQuestion, resolver_addr: SocketAddr, recursion_desired: bool) -> Result<DnsPacket> {
+    // A fresh random ID per attempt, never the client's, so an off-path
+    // attacker has to guess it along with the source port
+    let id = rand::random::<u16>();
+
+    let mut resolver_packet = DnsPacket::new();
+    resolver_packet.questions.push(question.clone());
+    resolver_packet.header.id = id;
+    resolver_packet.header.recursion_desired = recursion_desired;
+
+    // Write the resolver packet to the buffer
+    let mut request_buffer = BytePacketBuffer::new();
+    resolver_packet.write(&mut request_buffer)?;
+
+    let response = query_udp(request_buffer.as_slice(), &resolver_packet, resolver_addr).await?;
+    if !response.header.truncated_message {
+        return Ok(response);
+    }
+
+    println!("Truncated answer from {}, retrying over TCP", resolver_addr);
+    let response = query_tcp(request_buffer.as_slice(), resolver_addr).await?;
+    if !is_reply_to(&response, &resolver_packet) {
+        return Err(anyhow!("Mismatched TCP reply from {}", resolver_addr));
+    }
+
+    Ok(response)
+}
+
+/// Whether `response` answers `request`: same ID, and echoes the same
+/// question (RFC 5452 section 9.1)
+fn is_reply_to(response: &DnsPacket, request: &DnsPacket) -> bool {
+    response.header.response
+        && response.header.id == request.header.id
+        && response.questions.len() == request.questions.len()
+        && response.questions.iter().zip(&request.questions).all(|(a, b)| {
+            a.qtype == b.qtype && a.class == b.class && a.name.eq_ignore_ascii_case(&b.name)
+        })
+}
+
+/// Bind a UDP socket on a random high port, leaving it to the OS only if
+/// a few random picks are all taken
+async fn bind_random_port(resolver_addr: SocketAddr) -> Result<UdpSocket> {
+    let ip = match resolver_addr.ip() {
+        IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+    };
+
+    for _ in 0..8 {
+        let port = rand::thread_rng().gen_range(1024..=65535);
+        if let Ok(socket) = UdpSocket::bind(SocketAddr::new(ip, port)).await {
+            return Ok(socket);
+        }
+    }
+
+    Ok(UdpSocket::bind(SocketAddr::new(ip, 0)).await?)
+}
+
+async fn query_udp(request: &[u8], request_packet: &DnsPacket, resolver_addr: SocketAddr) -> Result<DnsPacket> {
+    // Send the question to the resolver
+    let resolver_socket = bind_random_port(resolver_addr).await?;
+    resolver_socket.send_to(request, resolver_addr).await?;
+
+    // Wait for the response from the resolver, ignoring anything that isn't
+    // a reply from it to this exact query. The caller's timeout bounds this.
+    let mut resolver_data = vec![0u8; TCP_MAX_SIZE];
+    loop {
+        let (response_size, src) = resolver_socket.recv_from(&mut resolver_data).await?;
+        if src != resolver_addr {
+            println!("Ignoring datagram from unexpected source {}", src);
+            continue;
+        }
+
+        // Parse the resolver's response
+        let mut resolver_response_buffer = BytePacketBuffer::from_bytes(&resolver_data[..response_size]);
+        let response = match DnsPacket::from_buffer(&mut resolver_response_buffer) {
+            Ok(response) => response,
+            Err(e) => {
+                println!("Ignoring malformed reply from {}: {}", src, e);
+                continue;
+            }
+        };
+
+        if !is_reply_to(&response, request_packet) {
+            println!("Ignoring mismatched reply from {}", src);
+            continue;
+        }
+
+        return Ok(response);
+    }
+}
+
+async fn query_tcp(request: &[u8], resolver_addr: SocketAddr) -> Result<DnsPacket> {
+    let mut stream = TcpStream::connect(resolver_addr).await?;
+
+    // Each message on a TCP stream carries a two byte length prefix
+    stream.write_all(&(request.len() as u16).to_be_bytes()).await?;
+    stream.write_all(request).await?;
+
+    let mut len_bytes = [0u8; 2];
+    stream.read_exact(&mut len_bytes).await?;
+    let mut data = vec![0u8; u16::from_be_bytes(len_bytes) as usize];
+    stream.read_exact(&mut data).await?;
+
+    let mut buffer = BytePacketBuffer::from_bytes(&data);
+    DnsPacket::from_buffer(&mut buffer)
+}
@@ -0,0 +1,371 @@
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+
+use anyhow::{Result, anyhow};
+
+use crate::{
+    header::ResultCode,
+    packet::DnsPacket,
+    query::{DnsQuestion, QueryClass, QueryType},
+    name::{is_subdomain, label_count, normalize, parent},
+    record::DnsRecord,
+    zone_file,
+};
+
+/// CNAMEs followed inside a zone before giving up on the chain
+const MAX_CNAME_CHAIN: usize = 8;
+
+/// The records of one zone we answer for authoritatively
+#[derive(Clone, Debug)]
+pub struct Zone {
+    /// Lowercased, without the trailing dot
+    pub origin: String,
+    class: QueryClass,
+    soa: DnsRecord,
+    /// Records by lowercased owner name
+    records: HashMap<String, Vec<DnsRecord>>,
+    /// Every name that exists in the zone, empty non-terminals included
+    names: HashSet<String>,
+}
+
+impl Zone {
+    /// Load a zone from a master file. Without an explicit origin the owner
+    /// of the file's SOA record becomes the origin, so the file has to use
+    /// absolute names or set its own `$ORIGIN`. The root zone can't be
+    /// loaded.
+    pub fn load(path: &Path, origin: Option<&str>) -> Result<Zone> {
+        let records = zone_file::parse(path, origin.unwrap_or(""))?;
+
+        let origin = match origin {
+            Some(origin) => origin.to_string(),
+            None => records
+                .iter()
+                .find(|rec| rec.qtype() == QueryType::SOA)
+                .map(|soa| soa.domain().to_string())
+                .ok_or_else(|| anyhow!("{}: no SOA record", path.display()))?,
+        };
+        if normalize(&origin).is_empty() {
+            return Err(anyhow!("{}: the root zone can't be served", path.display()));
+        }
+
+        Zone::new(&origin, records).map_err(|e| anyhow!("{}: {}", path.display(), e))
+    }
+
+    pub fn new(origin: &str, records: Vec<DnsRecord>) -> Result<Zone> {
+        let origin = normalize(origin);
+
+        let soa = records
+            .iter()
+            .find(|rec| rec.qtype() == QueryType::SOA && normalize(rec.domain()) == origin)
+            .cloned()
+            .ok_or_else(|| anyhow!("No SOA record at the zone apex {}", origin))?;
+        let class = soa.class();
+
+        let mut by_name: HashMap<String, Vec<DnsRecord>> = HashMap::new();
+        let mut names = HashSet::new();
+        for rec in records {
+            let name = normalize(rec.domain());
+            if !is_subdomain(&name, &origin) {
+                println!("Ignoring {} {:?}, which is outside zone {}", name, rec.qtype(), origin);
+                continue;
+            }
+            if rec.class() != class {
+                return Err(anyhow!("{} is in class {:?} but the zone is {:?}", name, rec.class(), class));
+            }
+            if rec.qtype() == QueryType::SOA && name != origin {
+                return Err(anyhow!("SOA record for {} is not at the zone apex", name));
+            }
+
+            // A name and all of its ancestors up to the apex exist
+            let mut ancestor = name.as_str();
+            while names.insert(ancestor.to_string()) && ancestor != origin {
+                ancestor = parent(ancestor);
+            }
+
+            let rrsets = by_name.entry(name).or_default();
+            if !rrsets.contains(&rec) {
+                rrsets.push(rec);
+            }
+        }
+
+        for (name, records) in &by_name {
+            let has_cname = records.iter().any(|rec| rec.qtype() == QueryType::CNAME);
+            if has_cname && records.iter().any(|rec| rec.qtype() != QueryType::CNAME) {
+                return Err(anyhow!("{} has a CNAME alongside other data", name));
+            }
+        }
+
+        Ok(Zone {
+            origin,
+            class,
+            soa,
+            records: by_name,
+            names,
+        })
+    }
+
+    /// The authoritative response to `question`, which must be in this zone
+    pub fn answer(&self, question: &DnsQuestion) -> DnsPacket {
+        let mut packet = DnsPacket::new();
+        packet.header.authoritative_answer = true;
+        packet.questions.push(question.clone());
+
+        // `owner` keeps the spelling the client used, for synthesised records
+        let mut owner = question.name.clone();
+        let mut name = normalize(&owner);
+        for _ in 0..MAX_CNAME_CHAIN {
+            // At or below a zone cut the data belongs to the child zone, so
+            // refer the client to its nameservers
+            if let Some(ns) = self.delegation(&name) {
+                packet.header.authoritative_answer = !packet.answers.is_empty();
+                packet.resources = self.glue(&ns);
+                packet.authorities = ns;
+                return packet;
+            }
+
+            let records: Vec<DnsRecord> = if self.names.contains(&name) {
+                // Empty non-terminals exist but own nothing
+                self.records.get(&name).cloned().unwrap_or_default()
+            } else if let Some(wildcard) = self.wildcard(&name) {
+                wildcard
+                    .iter()
+                    .map(|rec| {
+                        let mut rec = rec.clone();
+                        rec.set_domain(owner.clone());
+                        rec
+                    })
+                    .collect()
+            } else {
+                packet.header.rescode = ResultCode::NXDOMAIN;
+                packet.authorities.push(self.negative_soa());
+                return packet;
+            };
+
+            let matching: Vec<DnsRecord> = records
+                .iter()
+                .filter(|rec| rec.qtype() == question.qtype)
+                .cloned()
+                .collect();
+            if !matching.is_empty() {
+                packet.answers.extend(matching);
+                return packet;
+            }
+
+            // Follow an alias as far as this zone can take it
+            match records.iter().find(|rec| rec.qtype() == QueryType::CNAME) {
+                Some(cname @ DnsRecord::CNAME { host, .. }) => {
+                    packet.answers.push(cname.clone());
+                    name = normalize(host);
+                    owner = host.clone();
+                    if !is_subdomain(&name, &self.origin) {
+                        return packet;
+                    }
+                }
+                _ => {
+                    packet.authorities.push(self.negative_soa());
+                    return packet;
+                }
+            }
+        }
+
+        packet
+    }
+
+    /// The records of the wildcard covering `name`, a name that doesn't
+    /// exist: the `*` label directly below its closest encloser, the nearest
+    /// ancestor that does (RFC 4592 section 3.3.1). A wildcard that is only
+    /// an empty non-terminal still matches, but owns nothing.
+    fn wildcard(&self, name: &str) -> Option<&[DnsRecord]> {
+        let mut encloser = name;
+        while !self.names.contains(encloser) {
+            if encloser.is_empty() {
+                return None;
+            }
+            encloser = parent(encloser);
+        }
+
+        let source = if encloser.is_empty() {
+            "*".to_string()
+        } else {
+            format!("*.{}", encloser)
+        };
+        if !self.names.contains(&source) {
+            return None;
+        }
+
+        Some(self.records.get(&source).map_or(&[], |records| records.as_slice()))
+    }
+
+    /// The NS records of the topmost zone cut between the apex and `name`,
+    /// `name` included, if part of that path has been delegated away
+    fn delegation(&self, name: &str) -> Option<Vec<DnsRecord>> {
+        let labels: Vec<&str> = name.split('.').filter(|l| !l.is_empty()).collect();
+        let apex_labels = label_count(&self.origin);
+
+        (apex_labels + 1..=labels.len())
+            .map(|count| labels[labels.len() - count..].join("."))
+            .find_map(|cut| {
+                let ns: Vec<DnsRecord> = self
+                    .records
+                    .get(&cut)?
+                    .iter()
+                    .filter(|rec| rec.qtype() == QueryType::NS)
+                    .cloned()
+                    .collect();
+                (!ns.is_empty()).then_some(ns)
+            })
+    }
+
+    /// A and AAAA records for the nameservers of a delegation that live
+    /// inside this zone, which a resolver couldn't look up without them
+    fn glue(&self, ns: &[DnsRecord]) -> Vec<DnsRecord> {
+        ns.iter()
+            .filter_map(|rec| match rec {
+                DnsRecord::NS { host, .. } => Some(normalize(host)),
+                _ => None,
+            })
+            .filter(|host| is_subdomain(host, &self.origin))
+            .filter_map(|host| self.records.get(&host))
+            .flatten()
+            .filter(|rec| matches!(rec.qtype(), QueryType::A | QueryType::AAAA))
+            .cloned()
+            .collect()
+    }
+
+    /// The SOA for NXDOMAIN and NODATA answers, its TTL lowered to the
+    /// negative caching TTL (RFC 2308 section 3)
+    fn negative_soa(&self) -> DnsRecord {
+        let mut soa = self.soa.clone();
+        if let DnsRecord::SOA { minimum, ttl, .. } = &self.soa {
+            soa.set_ttl((*ttl).min(*minimum));
+        }
+        soa
+    }
+}
+
+/// The zones we are authoritative for
+#[derive(Debug, Default)]
+pub struct Zones {
+    zones: Vec<Zone>,
+}
+
+impl Zones {
+    pub fn new(zones: Vec<Zone>) -> Zones {
+        Zones { zones }
+    }
+
+    /// The authoritative response to `question`, if it falls in one of our
+    /// zones. The deepest zone wins when one of ours is inside another.
+    pub fn answer(&self, question: &DnsQuestion) -> Option<DnsPacket> {
+        let name = normalize(&question.name);
+
+        self.zones
+            .iter()
+            .filter(|zone| zone.class == question.class && is_subdomain(&name, &zone.origin))
+            .max_by_key(|zone| zone.origin.len())
+            .map(|zone| zone.answer(question))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{a, ns, soa};
+
+    /// The `example.com` zone with `records` besides its SOA
+    fn zone(records: Vec<DnsRecord>) -> Zone {
+        let mut all = vec![soa(3600, 60)];
+        all.extend(records);
+        Zone::new("example.com.", all).unwrap()
+    }
+
+    fn ask(zone: &Zone, name: &str) -> DnsPacket {
+        zone.answer(&DnsQuestion::new(name.to_string(), QueryType::A))
+    }
+
+    #[test]
+    fn answers_are_authoritative() {
+        let response = ask(&zone(vec![a("www.example.com", [192, 0, 2, 1], 300)]), "WWW.example.com");
+
+        assert_eq!(response.header.rescode, ResultCode::NOERROR);
+        assert!(response.header.authoritative_answer);
+        assert_eq!(response.answers, vec![a("www.example.com", [192, 0, 2, 1], 300)]);
+    }
+
+    #[test]
+    fn missing_data_is_denied_with_the_negative_ttl() {
+        // Makes b.example.com an empty non-terminal
+        let zone = zone(vec![a("a.b.example.com", [192, 0, 2, 1], 300)]);
+
+        let response = ask(&zone, "nowhere.example.com");
+        assert_eq!(response.header.rescode, ResultCode::NXDOMAIN);
+        assert!(response.header.authoritative_answer);
+        assert_eq!(response.authorities, vec![soa(60, 60)]);
+
+        // The empty non-terminal exists, so it is NODATA
+        let response = ask(&zone, "b.example.com");
+        assert_eq!(response.header.rescode, ResultCode::NOERROR);
+        assert!(response.answers.is_empty());
+        assert_eq!(response.authorities, vec![soa(60, 60)]);
+    }
+
+    #[test]
+    fn wildcard_answers_for_missing_names() {
+        let zone = zone(vec![
+            a("*.example.com", [192, 0, 2, 1], 300),
+            a("www.example.com", [192, 0, 2, 2], 300),
+        ]);
+
+        // The owner is rewritten to the name asked for, as it was spelled
+        let response = ask(&zone, "Nowhere.example.com");
+        assert_eq!(response.header.rescode, ResultCode::NOERROR);
+        assert_eq!(response.answers, vec![a("Nowhere.example.com", [192, 0, 2, 1], 300)]);
+
+        // Names that exist are never synthesised
+        let response = ask(&zone, "www.example.com");
+        assert_eq!(response.answers, vec![a("www.example.com", [192, 0, 2, 2], 300)]);
+    }
+
+    #[test]
+    fn empty_non_terminal_blocks_the_wildcard() {
+        let zone = zone(vec![
+            a("*.example.com", [192, 0, 2, 1], 300),
+            // Makes b.example.com an empty non-terminal
+            a("a.b.example.com", [192, 0, 2, 2], 300),
+        ]);
+
+        // The empty non-terminal itself exists, so it is NODATA
+        let response = ask(&zone, "b.example.com");
+        assert_eq!(response.header.rescode, ResultCode::NOERROR);
+        assert!(response.answers.is_empty());
+
+        // It is the closest encloser for names below it, and has no wildcard
+        let response = ask(&zone, "x.b.example.com");
+        assert_eq!(response.header.rescode, ResultCode::NXDOMAIN);
+        assert!(response.answers.is_empty());
+    }
+
+    #[test]
+    fn referral_includes_glue() {
+        let zone = zone(vec![
+            ns("sub.example.com", "ns.sub.example.com"),
+            ns("sub.example.com", "ns.elsewhere.net"),
+            a("ns.sub.example.com", [192, 0, 2, 53], 300),
+        ]);
+
+        let response = ask(&zone, "www.sub.example.com");
+
+        assert_eq!(response.header.rescode, ResultCode::NOERROR);
+        assert!(!response.header.authoritative_answer);
+        assert!(response.answers.is_empty());
+        assert_eq!(
+            response.authorities,
+            vec![
+                ns("sub.example.com", "ns.sub.example.com"),
+                ns("sub.example.com", "ns.elsewhere.net"),
+            ]
+        );
+        // Only the nameserver inside the zone gets glue
+        assert_eq!(response.resources, vec![a("ns.sub.example.com", [192, 0, 2, 53], 300)]);
+    }
+}
@@ -0,0 +1,597 @@
+use std::fs;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::path::Path;
+use std::str::FromStr;
+
+use anyhow::{Context, Result, anyhow};
+
+use crate::{
+    query::{QueryClass, QueryType},
+    record::DnsRecord,
+};
+
+/// How deep `$INCLUDE`s may nest, so a file including itself fails cleanly
+const MAX_INCLUDE_DEPTH: usize = 8;
+
+/// One logical line of a zone file, with parenthesised continuation lines
+/// already joined on and comments dropped
+struct Entry {
+    line: usize,
+    /// The line started with whitespace, so the owner is the previous one
+    blank_owner: bool,
+    tokens: Vec<Token>,
+}
+
+struct Token {
+    text: String,
+    quoted: bool,
+}
+
+/// Read the records from an RFC 1035 master file (section 5). Relative
+/// names are taken to be below `origin` until a `$ORIGIN` says otherwise.
+pub fn parse(path: &Path, origin: &str) -> Result<Vec<DnsRecord>> {
+    let mut parser = Parser {
+        origin: origin.trim_end_matches('.').to_string(),
+        default_ttl: None,
+        last_ttl: None,
+        last_owner: None,
+        last_class: QueryClass::IN,
+        records: Vec::new(),
+    };
+    parser.parse_file(path, 0)?;
+
+    Ok(parser.records)
+}
+
+struct Parser {
+    origin: String,
+    /// Set by `$TTL` (RFC 2308 section 4)
+    default_ttl: Option<u32>,
+    /// The last TTL given explicitly, used when there is no `$TTL`
+    last_ttl: Option<u32>,
+    last_owner: Option<String>,
+    last_class: QueryClass,
+    records: Vec<DnsRecord>,
+}
+
+impl Parser {
+    fn parse_file(&mut self, path: &Path, depth: usize) -> Result<()> {
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("Could not read zone file {}", path.display()))?;
+
+        let entries = tokenize(&text).map_err(|e| anyhow!("{}: {}", path.display(), e))?;
+        for entry in entries {
+            self.parse_entry(&entry, path, depth)
+                .map_err(|e| anyhow!("{}:{}: {}", path.display(), entry.line, e))?;
+        }
+
+        Ok(())
+    }
+
+    fn parse_entry(&mut self, entry: &Entry, path: &Path, depth: usize) -> Result<()> {
+        let mut tokens = entry.tokens.iter();
+
+        let first = &entry.tokens[0];
+        if !entry.blank_owner && !first.quoted && first.text.starts_with('$') {
+            tokens.next();
+            return self.parse_directive(&first.text, tokens.collect(), path, depth);
+        }
+
+        let owner = if entry.blank_owner {
+            self.last_owner
+                .clone()
+                .ok_or_else(|| anyhow!("Record has no owner name and there is no previous one"))?
+        } else {
+            self.name(&first.text)?
+        };
+        if !entry.blank_owner {
+            tokens.next();
+        }
+
+        // TTL and class are both optional and may come in either order
+        let mut ttl = None;
+        let mut class = None;
+        let rtype = loop {
+            let token = tokens.next().ok_or_else(|| anyhow!("Missing record type"))?;
+            match (parse_class(&token.text), parse_ttl(&token.text)) {
+                (Some(c), _) if class.is_none() => class = Some(c),
+                (_, Some(t)) if ttl.is_none() => ttl = Some(t),
+                _ => break token.text.to_uppercase(),
+            }
+        };
+
+        if ttl.is_some() {
+            self.last_ttl = ttl;
+        }
+        let ttl = ttl
+            .or(self.default_ttl)
+            .or(self.last_ttl)
+            .ok_or_else(|| anyhow!("Record has no TTL and there is no $TTL"))?;
+
+        let class = class.unwrap_or(self.last_class);
+        self.last_class = class;
+
+        let record = self.record(owner.clone(), class, ttl, &rtype, &tokens.collect::<Vec<_>>())?;
+        self.records.push(record);
+        self.last_owner = Some(owner);
+
+        Ok(())
+    }
+
+    fn parse_directive(&mut self, directive: &str, args: Vec<&Token>, path: &Path, depth: usize) -> Result<()> {
+        match (directive.to_uppercase().as_str(), args.as_slice()) {
+            ("$ORIGIN", [origin]) => self.origin = self.name(&origin.text)?,
+            ("$TTL", [ttl]) => {
+                self.default_ttl = Some(parse_ttl(&ttl.text).ok_or_else(|| anyhow!("Invalid TTL: {}", ttl.text))?)
+            }
+            ("$INCLUDE", [file, rest @ ..]) if rest.len() <= 1 => {
+                if depth >= MAX_INCLUDE_DEPTH {
+                    return Err(anyhow!("$INCLUDE nested more than {} deep", MAX_INCLUDE_DEPTH));
+                }
+
+                // The included file may set its own origin, but that never
+                // leaks back into this one (RFC 1035 section 5.1)
+                let saved_origin = self.origin.clone();
+                if let Some(origin) = rest.first() {
+                    self.origin = self.name(&origin.text)?;
+                }
+
+                let included = path.parent().unwrap_or(Path::new(".")).join(&file.text);
+                let result = self.parse_file(&included, depth + 1);
+                self.origin = saved_origin;
+                result?;
+            }
+            _ => return Err(anyhow!("Invalid directive: {}", directive)),
+        }
+
+        Ok(())
+    }
+
+    /// An owner or RDATA name made absolute, without the trailing dot.
+    /// `@` and relative names need an origin to be made absolute against.
+    fn name(&self, text: &str) -> Result<String> {
+        if let Some(name) = text.strip_suffix('.') {
+            Ok(name.to_string())
+        } else if self.origin.is_empty() {
+            Err(anyhow!("{} is not absolute and there is no $ORIGIN", text))
+        } else if text == "@" {
+            Ok(self.origin.clone())
+        } else {
+            Ok(format!("{}.{}", text, self.origin))
+        }
+    }
+
+    fn record(&self, domain: String, class: QueryClass, ttl: u32, rtype: &str, rdata: &[&Token]) -> Result<DnsRecord> {
+        let fields = |count: usize| -> Result<Vec<&str>> {
+            if rdata.len() != count {
+                return Err(anyhow!("{} record needs {} fields, found {}", rtype, count, rdata.len()));
+            }
+            Ok(rdata.iter().map(|token| token.text.as_str()).collect())
+        };
+
+        let record = match rtype {
+            "A" => DnsRecord::A {
+                domain,
+                class,
+                addr: parse_field::<Ipv4Addr>(fields(1)?[0], "IPv4 address")?,
+                ttl,
+            },
+            "AAAA" => DnsRecord::AAAA {
+                domain,
+                class,
+                addr: parse_field::<Ipv6Addr>(fields(1)?[0], "IPv6 address")?,
+                ttl,
+            },
+            "NS" => DnsRecord::NS {
+                domain,
+                class,
+                host: self.name(fields(1)?[0])?,
+                ttl,
+            },
+            "CNAME" => DnsRecord::CNAME {
+                domain,
+                class,
+                host: self.name(fields(1)?[0])?,
+                ttl,
+            },
+            "PTR" => DnsRecord::PTR {
+                domain,
+                class,
+                host: self.name(fields(1)?[0])?,
+                ttl,
+            },
+            "MX" => {
+                let fields = fields(2)?;
+                DnsRecord::MX {
+                    domain,
+                    class,
+                    priority: parse_field(fields[0], "preference")?,
+                    host: self.name(fields[1])?,
+                    ttl,
+                }
+            }
+            "SRV" => {
+                let fields = fields(4)?;
+                DnsRecord::SRV {
+                    domain,
+                    class,
+                    priority: parse_field(fields[0], "priority")?,
+                    weight: parse_field(fields[1], "weight")?,
+                    port: parse_field(fields[2], "port")?,
+                    target: self.name(fields[3])?,
+                    ttl,
+                }
+            }
+            "SOA" => {
+                let fields = fields(7)?;
+                let timer = |text: &str| parse_ttl(text).ok_or_else(|| anyhow!("Invalid SOA timer: {}", text));
+                DnsRecord::SOA {
+                    domain,
+                    class,
+                    mname: self.name(fields[0])?,
+                    rname: self.name(fields[1])?,
+                    serial: parse_field(fields[2], "serial")?,
+                    refresh: timer(fields[3])?,
+                    retry: timer(fields[4])?,
+                    expire: timer(fields[5])?,
+                    minimum: timer(fields[6])?,
+                    ttl,
+                }
+            }
+            "TXT" => {
+                if rdata.is_empty() {
+                    return Err(anyhow!("TXT record needs at least one string"));
+                }
+                DnsRecord::TXT {
+                    domain,
+                    class,
+                    strings: rdata
+                        .iter()
+                        .map(|token| character_string(&token.text))
+                        .collect::<Result<_>>()?,
+                    ttl,
+                }
+            }
+            _ => {
+                // Anything else must be in the RFC 3597 generic form:
+                // TYPE<n> \# <length> <hex>...
+                let qtype = rtype
+                    .strip_prefix("TYPE")
+                    .and_then(|num| num.parse::<u16>().ok())
+                    .ok_or_else(|| anyhow!("Unsupported record type: {}", rtype))?;
+                if !matches!(QueryType::from_num(qtype), QueryType::UNKNOWN(_)) {
+                    return Err(anyhow!("{} has its own syntax, use the type name instead", rtype));
+                }
+
+                DnsRecord::UNKNOWN {
+                    domain,
+                    class,
+                    qtype,
+                    data: generic_rdata(rdata)?,
+                    ttl,
+                }
+            }
+        };
+
+        Ok(record)
+    }
+}
+
+/// Split a zone file into entries, handling quoting, comments and
+/// parentheses
+fn tokenize(text: &str) -> Result<Vec<Entry>> {
+    let mut entries = Vec::new();
+    let mut entry = Entry {
+        line: 1,
+        blank_owner: false,
+        tokens: Vec::new(),
+    };
+    let mut word = String::new();
+    let mut parens = 0;
+    let mut line = 1;
+    let mut line_start = true;
+
+    let flush = |word: &mut String, entry: &mut Entry| {
+        if !word.is_empty() {
+            entry.tokens.push(Token {
+                text: std::mem::take(word),
+                quoted: false,
+            });
+        }
+    };
+
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        if line_start && parens == 0 {
+            entry.line = line;
+            entry.blank_owner = c == ' ' || c == '\t';
+        }
+        line_start = false;
+
+        match c {
+            '\n' => {
+                flush(&mut word, &mut entry);
+                line += 1;
+                line_start = true;
+                if parens == 0 && !entry.tokens.is_empty() {
+                    entries.push(Entry {
+                        line: entry.line,
+                        blank_owner: entry.blank_owner,
+                        tokens: std::mem::take(&mut entry.tokens),
+                    });
+                }
+            }
+            ';' => {
+                flush(&mut word, &mut entry);
+                while chars.next_if(|&c| c != '\n').is_some() {}
+            }
+            '(' => {
+                flush(&mut word, &mut entry);
+                parens += 1;
+            }
+            ')' => {
+                flush(&mut word, &mut entry);
+                if parens == 0 {
+                    return Err(anyhow!("line {}: ')' without '('", line));
+                }
+                parens -= 1;
+            }
+            '"' => {
+                flush(&mut word, &mut entry);
+                let mut quoted = String::new();
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        // Escapes are kept and decoded along with the RDATA
+                        Some('\\') => {
+                            quoted.push('\\');
+                            quoted.extend(chars.next());
+                        }
+                        Some('\n') | None => return Err(anyhow!("line {}: unterminated string", line)),
+                        Some(c) => quoted.push(c),
+                    }
+                }
+                entry.tokens.push(Token { text: quoted, quoted: true });
+            }
+            '\\' => {
+                word.push(c);
+                word.extend(chars.next());
+            }
+            c if c.is_whitespace() => flush(&mut word, &mut entry),
+            c => word.push(c),
+        }
+    }
+
+    flush(&mut word, &mut entry);
+    if parens > 0 {
+        return Err(anyhow!("line {}: '(' never closed", entry.line));
+    }
+    if !entry.tokens.is_empty() {
+        entries.push(entry);
+    }
+
+    Ok(entries)
+}
+
+fn parse_class(text: &str) -> Option<QueryClass> {
+    match text.to_uppercase().as_str() {
+        "IN" => Some(QueryClass::IN),
+        "CH" => Some(QueryClass::CH),
+        "HS" => Some(QueryClass::HS),
+        _ => None,
+    }
+}
+
+/// A TTL in seconds, or with BIND style units such as `1h30m` or `2d`
+fn parse_ttl(text: &str) -> Option<u32> {
+    if text.is_empty() {
+        return None;
+    }
+    if let Ok(secs) = text.parse::<u32>() {
+        return Some(secs);
+    }
+
+    let mut total: u32 = 0;
+    let mut num = String::new();
+    for c in text.chars() {
+        if c.is_ascii_digit() {
+            num.push(c);
+            continue;
+        }
+
+        let unit = match c.to_ascii_lowercase() {
+            'w' => 604800,
+            'd' => 86400,
+            'h' => 3600,
+            'm' => 60,
+            's' => 1,
+            _ => return None,
+        };
+        let value: u32 = num.parse().ok()?;
+        total = total.checked_add(value.checked_mul(unit)?)?;
+        num.clear();
+    }
+
+    num.is_empty().then_some(total)
+}
+
+fn parse_field<T: FromStr>(text: &str, what: &str) -> Result<T> {
+    text.parse::<T>()
+        .map_err(|_| anyhow!("Invalid {}: {}", what, text))
+}
+
+/// A TXT string with its `\X` and `\DDD` escapes decoded
+fn character_string(text: &str) -> Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    let mut chars = text.chars();
+
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            let mut utf8 = [0; 4];
+            bytes.extend_from_slice(c.encode_utf8(&mut utf8).as_bytes());
+            continue;
+        }
+
+        match chars.next() {
+            Some(d) if d.is_ascii_digit() => {
+                let digits: String = [Some(d), chars.next(), chars.next()].into_iter().flatten().collect();
+                let byte = digits
+                    .parse::<u8>()
+                    .ok()
+                    .filter(|_| digits.len() == 3)
+                    .ok_or_else(|| anyhow!("Invalid escape: \\{}", digits))?;
+                bytes.push(byte);
+            }
+            Some(c) => {
+                let mut utf8 = [0; 4];
+                bytes.extend_from_slice(c.encode_utf8(&mut utf8).as_bytes());
+            }
+            None => return Err(anyhow!("String ends in a lone backslash")),
+        }
+    }
+
+    if bytes.len() > 255 {
+        return Err(anyhow!("String is longer than 255 bytes"));
+    }
+
+    Ok(bytes)
+}
+
+/// RDATA written as `\# <length> <hex>...` (RFC 3597 section 5)
+fn generic_rdata(rdata: &[&Token]) -> Result<Vec<u8>> {
+    let [marker, len, hex @ ..] = rdata else {
+        return Err(anyhow!("Generic RDATA must look like \\# <length> <hex>"));
+    };
+    if marker.text != "\\#" {
+        return Err(anyhow!("Generic RDATA must start with \\#"));
+    }
+
+    let len: usize = parse_field(&len.text, "RDATA length")?;
+    let hex: String = hex.iter().map(|token| token.text.as_str()).collect();
+    if !hex.is_ascii() || hex.len() % 2 != 0 {
+        return Err(anyhow!("Invalid hex in RDATA: {}", hex));
+    }
+
+    let data = (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).map_err(|_| anyhow!("Invalid hex in RDATA: {}", hex)))
+        .collect::<Result<Vec<u8>>>()?;
+    if data.len() != len {
+        return Err(anyhow!("RDATA is {} bytes, expected {}", data.len(), len));
+    }
+
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+    use crate::testing::{a, soa};
+
+    /// Write `files` into a fresh directory and return the path of the first
+    fn write_zone(test: &str, files: &[(&str, &str)]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("zone_file_{}_{}", std::process::id(), test));
+        fs::create_dir_all(&dir).unwrap();
+        for (name, text) in files {
+            fs::write(dir.join(name), text).unwrap();
+        }
+        dir.join(files[0].0)
+    }
+
+    #[test]
+    fn directives_set_origin_ttl_and_include() {
+        let path = write_zone(
+            "directives",
+            &[
+                (
+                    "example.zone",
+                    "$ORIGIN example.com.\n\
+                     www 60 A 192.0.2.1\n\
+                     $TTL 1h\n\
+                     mail A 192.0.2.2\n\
+                     $INCLUDE sub.zone sub\n\
+                     after A 192.0.2.3\n",
+                ),
+                (
+                    "sub.zone",
+                    "host A 192.0.2.4\n\
+                     $ORIGIN other.test.\n\
+                     x 30 A 192.0.2.5\n",
+                ),
+            ],
+        );
+
+        let records = parse(&path, "ignored.test").unwrap();
+
+        assert_eq!(
+            records,
+            vec![
+                a("www.example.com", [192, 0, 2, 1], 60),
+                // $TTL wins over the last explicit TTL
+                a("mail.example.com", [192, 0, 2, 2], 3600),
+                a("host.sub.example.com", [192, 0, 2, 4], 3600),
+                a("x.other.test", [192, 0, 2, 5], 30),
+                // The included file's $ORIGIN doesn't leak back out
+                a("after.example.com", [192, 0, 2, 3], 3600),
+            ]
+        );
+    }
+
+    #[test]
+    fn relative_names_need_an_origin() {
+        let path = write_zone("no_origin", &[("example.zone", "www 60 A 192.0.2.1\n")]);
+        assert!(parse(&path, "").is_err());
+
+        let path = write_zone("absolute", &[("example.zone", "www.example.com. 60 A 192.0.2.1\n")]);
+        assert_eq!(parse(&path, "").unwrap(), vec![a("www.example.com", [192, 0, 2, 1], 60)]);
+    }
+
+    #[test]
+    fn include_loop_fails() {
+        let path = write_zone("loop", &[("loop.zone", "$INCLUDE loop.zone\n")]);
+
+        assert!(parse(&path, "example.com").is_err());
+    }
+
+    #[test]
+    fn parentheses_and_blank_owners_continue_the_record() {
+        let path = write_zone(
+            "continuation",
+            &[(
+                "example.zone",
+                "$TTL 300\n\
+                 @ IN SOA ns1 hostmaster (\n\
+                 \x20       1          ; serial\n\
+                 \x20       1h 10m 1d  ; refresh, retry, expire\n\
+                 \x20       60 )       ; minimum\n\
+                 \x20 NS ns1\n\
+                 ns1 A 192.0.2.1\n\
+                 \tAAAA 2001:db8::1\n",
+            )],
+        );
+
+        let records = parse(&path, "example.com.").unwrap();
+
+        assert_eq!(
+            records,
+            vec![
+                soa(300, 60),
+                DnsRecord::NS {
+                    domain: "example.com".to_string(),
+                    class: QueryClass::IN,
+                    host: "ns1.example.com".to_string(),
+                    ttl: 300,
+                },
+                a("ns1.example.com", [192, 0, 2, 1], 300),
+                DnsRecord::AAAA {
+                    domain: "ns1.example.com".to_string(),
+                    class: QueryClass::IN,
+                    addr: "2001:db8::1".parse().unwrap(),
+                    ttl: 300,
+                },
+            ]
+        );
+    }
+}